@@ -0,0 +1,311 @@
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::fmt;
+
+use crate::{quote_literal, PsqlServerError};
+
+/// A row returned by `PsqlConnection::query`, one string per column.
+pub type Row = Vec<String>;
+
+/// A row returned by `PsqlConnection::query_records`, which keeps the
+/// column names and tells NULL apart from an empty string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    columns: Arc<Vec<String>>,
+    values: Vec<Option<String>>
+}
+
+impl Record {
+    /// The value of `column`, `None` if it is NULL. Panics if there is no
+    /// such column.
+    pub fn get(&self, column: &str) -> Option<&str> {
+        let index = self.columns.iter().position(|name| name == column)
+            .unwrap_or_else(|| panic!("no column `{}` in {:?}", column, self.columns));
+        self.values[index].as_deref()
+    }
+
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    /// The values in column order, `None` for NULL.
+    pub fn values(&self) -> &[Option<String>] {
+        &self.values
+    }
+}
+
+static NEXT_SENTINEL_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A minimal connection to the server, backed by a long-running `psql`
+/// session so session state (`SET`, transactions, temp tables) persists
+/// between calls. Values are passed around as strings.
+pub struct PsqlConnection {
+    process: Child,
+    stdin: Option<ChildStdin>,
+    stdout: BufReader<ChildStdout>,
+    stderr: Receiver<String>,
+    sentinel: String,
+    /// See `take_notices`.
+    notices: Vec<String>
+}
+
+impl PsqlConnection {
+    /// Starts `psql` (already configured with connection arguments) and waits
+    /// until it has connected.
+    pub(crate) fn open(mut command: Command) -> Result<PsqlConnection, PsqlServerError> {
+        let mut process = command
+            .args(["-t", "--csv", "-v", "ON_ERROR_STOP=0"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(PsqlServerError::IoError)?;
+
+        let stdin = process.stdin.take();
+        let stdout = BufReader::new(process.stdout.take().unwrap());
+        let stderr_pipe = BufReader::new(process.stderr.take().unwrap());
+        let (stderr_sender, stderr) = mpsc::channel();
+        // stderr is read on its own thread so psql can never block on a
+        // full pipe while we wait for its stdout
+        thread::spawn(move || {
+            for line in stderr_pipe.lines() {
+                match line {
+                    Ok(line) => if stderr_sender.send(line).is_err() { break },
+                    Err(_) => break
+                }
+            }
+        });
+
+        let mut connection = PsqlConnection {
+            process,
+            stdin,
+            stdout,
+            stderr,
+            sentinel: format!("__testing_postgres_{}_{}__", std::process::id(),
+                              NEXT_SENTINEL_ID.fetch_add(1, Ordering::SeqCst)),
+            notices: Vec::new()
+        };
+        connection.run("")?;
+        Ok(connection)
+    }
+
+    /// Runs `sql`, discarding any rows it returns.
+    pub fn execute(&mut self, sql: &str) -> Result<(), PsqlServerError> {
+        self.run(sql).map(|_| ())
+    }
+
+    /// Runs `sql` and returns the rows it produced (for several statements,
+    /// the rows of each in turn).
+    pub fn query(&mut self, sql: &str) -> Result<Vec<Row>, PsqlServerError> {
+        let lines = self.run(sql)?;
+        parse_csv(&lines)
+    }
+
+    /// Like `query`, but returns `Record`s, with column names and NULLs as
+    /// `None`. `sql` should return a single result, as the column names of
+    /// several couldn't be told apart from their rows.
+    pub fn query_records(&mut self, sql: &str) -> Result<Vec<Record>, PsqlServerError> {
+        // psql prints NULL like an empty string unless told otherwise
+        let null = format!("{}_null", self.sentinel);
+        let lines = self.run(&format!(
+            "\\pset tuples_only off\n\\pset null {0}\n{1}\n;\n\\pset tuples_only on\n\\pset null ''",
+            null, sql))?;
+        let mut rows = parse_csv(&lines)?.into_iter();
+        let columns = match rows.next() {
+            Some(header) => Arc::new(header),
+            // a statement that returns no rows, not even an empty result
+            None => return Ok(Vec::new())
+        };
+        Ok(rows
+           .map(|row| Record {
+               columns: Arc::clone(&columns),
+               values: row.into_iter().map(|value| if value == null { None } else { Some(value) }).collect()
+           })
+           .collect())
+    }
+
+    /// Sets a configuration parameter for the rest of this session, like
+    /// `SET name = value`.
+    pub fn set_guc(&mut self, name: &str, value: &str) -> Result<(), PsqlServerError> {
+        self.execute(&format!("SELECT set_config({}, {}, false)",
+                              quote_literal(name), quote_literal(value)))
+    }
+
+    /// The current value of a configuration parameter, like `SHOW name`.
+    pub fn get_guc(&mut self, name: &str) -> Result<String, PsqlServerError> {
+        let rows = self.query(&format!("SELECT current_setting({})", quote_literal(name)))?;
+        Ok(rows.into_iter().next()
+           .and_then(|row| row.into_iter().next())
+           .unwrap_or_default())
+    }
+
+    /// The notices (NOTICE, WARNING, INFO, ...) the server sent since the
+    /// last call, oldest first, e.g. `NOTICE:  table "t" does not exist,
+    /// skipping`, with any DETAIL or HINT lines after a newline. Which are
+    /// sent depends on `client_min_messages`.
+    pub fn take_notices(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.notices)
+    }
+
+    /// Sends `sql` followed by markers on stdout and stderr, then collects
+    /// everything psql printed up to those markers.
+    fn run(&mut self, sql: &str) -> Result<Vec<String>, PsqlServerError> {
+        let stdin = self.stdin.as_mut()
+            .ok_or_else(|| PsqlServerError::PsqlFailed("connection is closed".to_owned()))?;
+        // the `;` makes sure a statement without a trailing semicolon is sent
+        // before the markers rather than left in psql's query buffer
+        let script = if sql.trim().is_empty() {
+            format!("\\echo {0}\n\\warn {0}\n", self.sentinel)
+        } else {
+            format!("{}\n;\n\\echo {1}\n\\warn {1}\n", sql, self.sentinel)
+        };
+        let written = stdin.write_all(script.as_bytes()).and_then(|_| stdin.flush());
+
+        let mut lines = Vec::new();
+        let mut finished = false;
+        loop {
+            let mut line = String::new();
+            match self.stdout.read_line(&mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            let line = line.trim_end_matches(&['\r', '\n'][..]);
+            if line == self.sentinel {
+                finished = true;
+                break;
+            }
+            lines.push(line.to_owned());
+        }
+
+        let mut errors = Vec::new();
+        for line in self.stderr.iter() {
+            if line == self.sentinel {
+                break;
+            }
+            errors.push(line);
+        }
+        self.collect_notices(&errors);
+
+        if !finished {
+            // psql exited, most likely because it could not connect
+            self.stdin.take();
+            let _ = self.process.wait();
+            let message = errors.join("\n");
+            return Err(match written {
+                Err(e) if message.is_empty() => PsqlServerError::IoError(e),
+                _ => PsqlServerError::PsqlFailed(message)
+            });
+        }
+        if errors.iter().any(|line| is_error_line(line)) {
+            return Err(PsqlServerError::PsqlFailed(errors.join("\n")));
+        }
+        Ok(lines)
+    }
+
+    /// Keeps the notices among what psql printed to stderr.
+    fn collect_notices(&mut self, errors: &[String]) {
+        let mut in_notice = false;
+        for line in errors {
+            let message = strip_psql_prefix(line);
+            if NOTICE_SEVERITIES.iter().any(|severity| message.starts_with(severity)) {
+                self.notices.push(message.to_owned());
+                in_notice = true;
+            } else if in_notice && NOTICE_FIELDS.iter().any(|field| message.starts_with(field)) {
+                let notice = self.notices.last_mut().unwrap();
+                notice.push('\n');
+                notice.push_str(message);
+            } else {
+                in_notice = false;
+            }
+        }
+    }
+}
+
+/// How psql starts the first line of a message that isn't an error.
+const NOTICE_SEVERITIES: &[&str] = &["WARNING:", "NOTICE:", "INFO:", "LOG:", "DEBUG:"];
+
+/// The lines psql prints after a message's first one.
+const NOTICE_FIELDS: &[&str] = &["DETAIL:", "HINT:", "CONTEXT:", "QUERY:", "LOCATION:"];
+
+/// Messages of the errors with SQLSTATE 57P03 (cannot_connect_now) and 57P02
+/// (crash_shutdown), which psql only reports as text when connecting.
+const CANNOT_CONNECT_NOW: &[&str] = &[
+    "the database system is starting up",
+    "the database system is not yet accepting connections",
+    "the database system is not accepting connections",
+    "the database system is in recovery mode",
+    "terminating connection because of crash of another server process"
+];
+
+/// Whether psql failed to connect because the server is starting up or
+/// recovering, so that a later attempt can succeed.
+pub(crate) fn is_cannot_connect_now(message: &str) -> bool {
+    CANNOT_CONNECT_NOW.iter().any(|text| message.contains(text))
+}
+
+/// Whether a line psql printed to stderr reports an error (as opposed to
+/// e.g. a NOTICE).
+fn is_error_line(line: &str) -> bool {
+    let message = strip_psql_prefix(line);
+    ["ERROR:", "FATAL:", "PANIC:", "invalid command"].iter()
+        .any(|prefix| message.starts_with(prefix))
+}
+
+/// `line` without the `psql:<stdin>:<line>: ` psql puts before messages
+/// about script input.
+fn strip_psql_prefix(line: &str) -> &str {
+    match line.strip_prefix("psql:") {
+        Some(rest) => rest.splitn(3, ':').nth(2).unwrap_or(rest).trim_start(),
+        None => line
+    }
+}
+
+/// Parses psql's `--csv` output, where quoted values may span lines.
+fn parse_csv(lines: &[String]) -> Result<Vec<Row>, PsqlServerError> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut value = String::new();
+    let mut in_quotes = false;
+    for line in lines {
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if in_quotes && chars.peek() == Some(&'"') => {
+                    chars.next();
+                    value.push('"');
+                },
+                '"' => in_quotes = !in_quotes,
+                ',' if !in_quotes => row.push(std::mem::take(&mut value)),
+                c => value.push(c)
+            }
+        }
+        if in_quotes {
+            value.push('\n');
+        } else {
+            row.push(std::mem::take(&mut value));
+            rows.push(std::mem::take(&mut row));
+        }
+    }
+    if in_quotes {
+        return Err(PsqlServerError::PsqlFailed("unterminated value in psql output".to_owned()));
+    }
+    Ok(rows)
+}
+
+impl fmt::Debug for PsqlConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PsqlConnection {{ pid: {} }}", self.process.id())
+    }
+}
+
+impl Drop for PsqlConnection {
+    fn drop(&mut self) {
+        // psql quits once its input is closed
+        self.stdin.take();
+        let _ = self.process.wait();
+    }
+}
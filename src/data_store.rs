@@ -0,0 +1,44 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tempdir::TempDir;
+
+/// The directory a server keeps its data, sockets and snapshots in. By
+/// default a `TempDir` in the system's temporary directory, see
+/// `PsqlServerBuilder::data_store` to use something else.
+pub trait DataStore: Send + Sync {
+    fn path(&self) -> &Path;
+
+    /// Removes the directory once the server has stopped.
+    fn close(self: Box<Self>) -> io::Result<()>;
+
+    /// Leaves the directory in place (see `CleanupPolicy`), returning its
+    /// path.
+    fn keep(self: Box<Self>) -> PathBuf;
+}
+
+impl DataStore for TempDir {
+    fn path(&self) -> &Path {
+        TempDir::path(self)
+    }
+
+    fn close(self: Box<Self>) -> io::Result<()> {
+        TempDir::close(*self)
+    }
+
+    fn keep(self: Box<Self>) -> PathBuf {
+        self.into_path()
+    }
+}
+
+/// Creates the `DataStore` of each server a builder starts.
+#[derive(Clone)]
+pub(crate) struct StoreFactory(pub(crate) Arc<dyn Fn() -> io::Result<Box<dyn DataStore>> + Send + Sync>);
+
+impl fmt::Debug for StoreFactory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StoreFactory")
+    }
+}
@@ -0,0 +1,90 @@
+use std::process::Command;
+
+use crate::{binary_version_num, find_tool, major_version, percent_encode, url_host, PsqlConnection, PsqlServerError};
+
+/// A server the crate didn't start, e.g. one shared by several test
+/// binaries, see `PsqlServer::connect_external_parts`. It is only connected
+/// to: dropping it leaves the server running and its databases alone.
+#[derive(Debug)]
+pub struct ExternalServer {
+    host: String,
+    user: String,
+    password: Option<String>,
+    database: String,
+    psql: String,
+    pub port: u16
+}
+
+impl ExternalServer {
+    /// Checks that `database` on the server accepts connections before
+    /// returning.
+    pub(crate) fn connect_parts(host: &str, port: u16, user: &str, password: Option<&str>, database: &str)
+                                -> Result<ExternalServer, PsqlServerError> {
+        let psql = find_tool("psql", None)
+            .map_err(|_| PsqlServerError::CouldNotFindPsqlCommand)?;
+        let version = binary_version_num(&psql)?;
+        // the connection relies on `\warn`, added in psql 13
+        if version < 130000 {
+            return Err(PsqlServerError::PsqlFailed(
+                format!("connect() needs psql 13 or newer, found {}", major_version(version))));
+        }
+        let server = ExternalServer {
+            host: host.to_owned(),
+            user: user.to_owned(),
+            password: password.map(str::to_owned),
+            database: database.to_owned(),
+            psql,
+            port
+        };
+        server.connect(database)?.execute("SELECT 1")?;
+        Ok(server)
+    }
+
+    /// Opens a `psql` session to `db`, like `PsqlServer::connect`.
+    pub fn connect(&self, db: &str) -> Result<PsqlConnection, PsqlServerError> {
+        PsqlConnection::open(self.psql_command(db))
+    }
+
+    /// A `postgres://` URL for the database given when connecting.
+    pub fn connection_string(&self) -> String {
+        let password = match &self.password {
+            Some(password) => format!(":{}", percent_encode(password)),
+            None => String::new()
+        };
+        format!("postgres://{}{}@{}:{}/{}",
+                percent_encode(&self.user), password, url_host(&self.host), self.port,
+                percent_encode(&self.database))
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    pub fn database(&self) -> &str {
+        &self.database
+    }
+
+    /// A `psql` command connected to `db` that stops on the first error.
+    /// Unlike the crate's own servers, TLS is left to `PGSSLMODE`.
+    fn psql_command(&self, db: &str) -> Command {
+        let mut command = Command::new(&self.psql);
+        if let Some(password) = &self.password {
+            command.env("PGPASSWORD", password);
+        }
+        command.args(["-X", "-q",
+                      "-v", "ON_ERROR_STOP=1",
+                      "-p", &format!("{}", self.port),
+                      "-h", &self.host,
+                      "-U", &self.user,
+                      "-d", db]);
+        command
+    }
+}
@@ -1,12 +1,39 @@
-use std::process::{Command, Child, Stdio};
+use std::process::{Command, Child, ExitStatus, Stdio};
+use std::collections::HashMap;
 use std::net::{SocketAddrV4, Ipv4Addr, TcpListener};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::fmt;
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::io::Read;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, Weak};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use tempdir::TempDir;
 
+use data_store::StoreFactory;
+use server_log::ServerLog;
+
+mod connection;
+mod data_store;
+mod external;
+mod pool;
+mod registry;
+mod replica_set;
+mod server_log;
+mod single_user;
+mod template;
+
+pub use connection::{PsqlConnection, Record, Row};
+pub use data_store::DataStore;
+pub use external::ExternalServer;
+pub use pool::{PooledServer, ServerPool};
+pub use replica_set::ReplicaSet;
+pub use single_user::SingleUserSession;
+pub use template::Template;
+
 fn which(command: &str) -> Result<String, ()> {
     let mut cmd = if cfg!(target_os = "windows") {
         Command::new("where")
@@ -25,29 +52,234 @@ fn which(command: &str) -> Result<String, ()> {
     }
 }
 
-fn get_unused_port() -> Result<u16, std::io::Error> {
+/// Finds `command` in `bin_dir` if given, falling back to `PATH`.
+fn find_tool(command: &str, bin_dir: Option<&Path>) -> Result<String, ()> {
+    if let Some(bin_dir) = bin_dir {
+        let path = bin_dir.join(format!("{}{}", command, std::env::consts::EXE_SUFFIX));
+        if path.is_file() {
+            return Ok(path.to_string_lossy().into_owned());
+        }
+    }
+    which(command)
+}
+
+/// Finds the directory holding the binaries of PostgreSQL `major`, looking at
+/// what is on `PATH` (including versioned names like `postgres-16`) and the
+/// usual per-version install locations of Debian, Red Hat and Homebrew.
+fn find_bin_dir(major: u32) -> Result<PathBuf, PsqlServerError> {
+    let mut candidates = Vec::new();
+    for name in &[format!("postgres-{}", major), "postgres".to_owned()] {
+        if let Some(dir) = which(name).ok()
+            .and_then(|path| fs::canonicalize(path).ok())
+            .and_then(|path| path.parent().map(Path::to_owned)) {
+                candidates.push(dir);
+            }
+    }
+    candidates.extend([
+        format!("/usr/lib/postgresql/{}/bin", major),
+        format!("/usr/pgsql-{}/bin", major),
+        format!("/opt/homebrew/opt/postgresql@{}/bin", major),
+        format!("/usr/local/opt/postgresql@{}/bin", major)
+    ].iter().map(PathBuf::from));
+
+    for dir in candidates {
+        let postgres = dir.join(format!("postgres{}", std::env::consts::EXE_SUFFIX));
+        let matches = postgres.is_file() && binary_version_num(&postgres.to_string_lossy())
+            .is_ok_and(|version_num| version_num / 10000 == major);
+        if matches {
+            return Ok(dir);
+        }
+    }
+    Err(PsqlServerError::VersionNotFound(major))
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{}", duration.as_millis())
+}
+
+fn on_off(enabled: bool) -> &'static str {
+    if enabled { "on" } else { "off" }
+}
+
+/// The longest unix socket path the platform accepts (`sun_path` minus the
+/// trailing nul).
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+          target_os = "openbsd", target_os = "netbsd", target_os = "dragonfly"))]
+const MAX_SOCKET_PATH_LEN: usize = 103;
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+              target_os = "openbsd", target_os = "netbsd", target_os = "dragonfly")))]
+const MAX_SOCKET_PATH_LEN: usize = 107;
+
+/// Whether postgres' socket file (`.s.PGSQL.<port>`) fits inside `dir`.
+fn socket_path_fits(dir: &Path) -> bool {
+    cfg!(not(unix))
+        || dir.as_os_str().len() + "/.s.PGSQL.65535".len() <= MAX_SOCKET_PATH_LEN
+}
+
+/// Checks that a user-chosen socket directory can hold the server's socket.
+fn check_socket_dir(dir: &Path) -> Result<(), PsqlServerError> {
+    let invalid = |error| PsqlServerError::InvalidSocketDir { path: dir.to_owned(), error };
+    if !fs::metadata(dir).map_err(invalid)?.is_dir() {
+        return Err(invalid(std::io::Error::other("not a directory")));
+    }
+    // creating something is the only reliable test for write access
+    TempDir::new_in(dir, "probe").and_then(TempDir::close).map_err(invalid)?;
+    if !socket_path_fits(dir) {
+        return Err(PsqlServerError::SocketPathTooLong(dir.to_owned()));
+    }
+    Ok(())
+}
+
+/// The `log_line_prefix` of captured logs: timestamp, pid, and for client
+/// sessions `user@database`.
+pub const DEFAULT_LOG_LINE_PREFIX: &str = "%m [%p] %q%u@%d ";
+
+/// How many of the last log lines `PsqlServerError::ServerCrashed` includes.
+const CRASH_LOG_LINES: usize = 20;
+
+/// How long a dropped server's postmaster gets to take its backends down
+/// before they are killed, see `kill_postgres`.
+#[cfg(unix)]
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many lines `PsqlServer::startup_log` keeps at most.
+const MAX_STARTUP_LOG_LINES: usize = 200;
+
+/// Binds a free port on the loopback interface. The port stays taken until
+/// the listener is dropped, just before postgres is spawned to bind it.
+fn reserve_unused_port() -> Result<(u16, TcpListener), std::io::Error> {
     let loopback = Ipv4Addr::new(127, 0, 0, 1);
     let socket = SocketAddrV4::new(loopback, 0);
     let listener = TcpListener::bind(socket)?;
     let port = listener.local_addr()?.port();
-    Ok(port)
+    Ok((port, listener))
 }
 
 pub struct PsqlServer {
-    process: Child,
-    base_dir: Option<TempDir>,
+    /// Shared with the thread watching for crashes, see
+    /// `PsqlServerBuilder::on_exit`.
+    process: Arc<Mutex<Option<Child>>>,
+    base_dir: Option<Box<dyn DataStore>>,
+    /// The directory shared with the other servers of a `start_many` call.
+    parent_dir: Option<Arc<SharedDir>>,
+    socket_dir: Option<TempDir>,
+    socket_path: PathBuf,
+    data_dir: PathBuf,
+    postgres: PostgresCommand,
+    pg_isready: String,
+    superuser: String,
+    password: Option<String>,
+    auth_method: AuthMethod,
+    database: String,
+    /// Set for a `unique_database_name` created in a cluster that outlives
+    /// the server.
+    drop_unique_database_on_drop: bool,
+    /// The host clients connect to, see `PsqlServerBuilder::connect_host`.
+    connect_host: String,
+    /// The host the crate's own tools connect to: the loopback address, or
+    /// the socket directory without TCP.
+    local_host: String,
+    /// Query parameters of the connection strings, see
+    /// `PsqlServerBuilder::connection_option`.
+    connection_options: Vec<(String, String)>,
+    snapshot_count: usize,
+    version_num: u32,
+    timings: StartupTimings,
+    /// Where the binaries of the requested major version were found.
+    bin_dir: Option<PathBuf>,
+    psql: OnceLock<(String, u32)>,
+    log: Arc<ServerLog>,
+    log_path: Option<PathBuf>,
+    startup_log: Vec<String>,
+    ran_initdb: bool,
+    cleanup: CleanupPolicy,
+    /// See `PsqlServerBuilder::assert_clean_on_drop`.
+    assert_clean: bool,
+    strict_clean: bool,
+    /// Set once `start` has succeeded, for `CleanupPolicy::OnSuccess`.
+    started: bool,
+    /// Set between `pause` and `resume`.
+    paused: AtomicBool,
+    /// See `export_repro_script`.
+    repro_script: String,
     pub port: u16
 }
 
+/// How long each phase of `start()` took. Finding the postgres binaries is
+/// not included, so the total reflects the cost of bringing up the cluster.
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// let server = testing_postgres::PsqlServer::start().unwrap();
+/// assert!(server.timings().total() < Duration::from_secs(3));
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StartupTimings {
+    /// Creating the data directory (initdb, or copying a base backup).
+    pub init: Duration,
+    /// From spawning postgres until it accepted connections.
+    pub ready: Duration,
+    /// Creating the `test` database.
+    pub create_database: Duration,
+    /// Post-startup setup such as creating preloaded extensions.
+    pub setup: Duration
+}
+
+impl StartupTimings {
+    pub fn total(&self) -> Duration {
+        self.init + self.ready + self.create_database + self.setup
+    }
+}
+
 #[derive(Debug)]
 pub enum PsqlServerError {
     CouldNotFindPostgresCommand,
     CouldNotFindInitDbCommand,
     CouldNotFindCreateDbCommand,
     CouldNotFindPgIsReadyCommand,
+    CouldNotFindPsqlCommand,
+    CouldNotFindPgCtlCommand,
+    CouldNotFindPgBasebackupCommand,
+    CouldNotFindPgDumpCommand,
     InitDbFailed,
+    BaseBackupFailed(String),
+    PgDumpFailed(String),
     CreateDbFailed,
     PostgresFailed,
+    PostgresFatal(String),
+    /// Another process took the server's port before postgres could bind it.
+    PortInUse(u16),
+    PgIsReadyFailed(String),
+    StartupTimedOut,
+    PsqlFailed(String),
+    UnknownVersion(String),
+    VersionMismatch { data_dir: String, server: String },
+    VersionNotFound(u32),
+    VersionTooOld { what: String, required: String, server: String },
+    InvalidLsn(String),
+    NotAStandby,
+    TimedOut(String),
+    SocketPathTooLong(PathBuf),
+    InvalidSocketDir { path: PathBuf, error: std::io::Error },
+    ServerCrashed { status: ExitStatus, stderr_tail: Vec<String> },
+    ExtensionNotAvailable { name: String, missing: Vec<PathBuf> },
+    /// The server was compiled without an optional feature, e.g. `lz4`.
+    NotBuiltWith(String),
+    TlsConfig(String),
+    ConfigFile { path: PathBuf, error: std::io::Error },
+    MigrationFileFailed { file: PathBuf, stderr: String },
+    MigrationFailed(Box<dyn std::error::Error + Send + Sync>),
+    /// The schema after a migration's up and down steps, as a diff against
+    /// the schema before them.
+    IrreversibleMigration(String),
+    PsqlScriptFailed { file: PathBuf, code: Option<i32>, stderr: String },
+    DryRun(Vec<Vec<String>>),
+    ManagedPostgresArg(String),
+    /// The server's log was needed, but it goes to the parent's stderr,
+    /// see `PsqlServerBuilder::inherit_stdio`.
+    LogNotCaptured,
+    #[cfg(feature = "tokio-postgres")]
+    Client(tokio_postgres::Error),
     IoError(std::io::Error)
 }
 
@@ -65,12 +297,96 @@ impl std::fmt::Display for PsqlServerError {
                 write!(f, "Could not find `createdb` command"),
             PsqlServerError::CouldNotFindPgIsReadyCommand =>
                 write!(f, "Could not find `pg_isready` command"),
+            PsqlServerError::CouldNotFindPsqlCommand =>
+                write!(f, "Could not find `psql` command"),
+            PsqlServerError::CouldNotFindPgCtlCommand =>
+                write!(f, "Could not find `pg_ctl` command"),
+            PsqlServerError::CouldNotFindPgBasebackupCommand =>
+                write!(f, "Could not find `pg_basebackup` command"),
+            PsqlServerError::CouldNotFindPgDumpCommand =>
+                write!(f, "Could not find `pg_dump` command"),
             PsqlServerError::InitDbFailed =>
                 write!(f, "initdb failed"),
+            PsqlServerError::BaseBackupFailed(stderr) =>
+                write!(f, "pg_basebackup failed: {}", stderr),
+            PsqlServerError::PgDumpFailed(stderr) =>
+                write!(f, "pg_dump failed: {}", stderr),
             PsqlServerError::CreateDbFailed =>
                 write!(f, "createdb failed"),
             PsqlServerError::PostgresFailed =>
                 write!(f, "postgres failed"),
+            PsqlServerError::PostgresFatal(message) =>
+                write!(f, "postgres failed: {}", message),
+            PsqlServerError::PortInUse(port) =>
+                write!(f, "port {} is already in use", port),
+            PsqlServerError::PgIsReadyFailed(stderr) =>
+                write!(f, "pg_isready failed: {}", stderr),
+            PsqlServerError::StartupTimedOut =>
+                write!(f, "timed out waiting for postgres to start"),
+            PsqlServerError::PsqlFailed(stderr) =>
+                write!(f, "psql failed: {}", stderr),
+            PsqlServerError::UnknownVersion(output) =>
+                write!(f, "could not parse postgres version from `{}`", output),
+            PsqlServerError::VersionMismatch { data_dir, server } =>
+                write!(f, "data directory is for PostgreSQL {}, but the installed server is {}",
+                       data_dir, server),
+            PsqlServerError::VersionNotFound(major) =>
+                write!(f, "PostgreSQL {} is not installed", major),
+            PsqlServerError::VersionTooOld { what, required, server } =>
+                write!(f, "{} needs PostgreSQL {} or newer, but the installed server is {}",
+                       what, required, server),
+            PsqlServerError::InvalidLsn(lsn) =>
+                write!(f, "invalid LSN `{}`", lsn),
+            PsqlServerError::NotAStandby =>
+                write!(f, "server is not a standby"),
+            PsqlServerError::TimedOut(what) =>
+                write!(f, "timed out {}", what),
+            PsqlServerError::SocketPathTooLong(dir) =>
+                write!(f, "unix socket path in `{}` would exceed the platform limit of {} bytes",
+                       dir.display(), MAX_SOCKET_PATH_LEN),
+            PsqlServerError::InvalidSocketDir { path, error } =>
+                write!(f, "cannot use `{}` as socket directory: {}", path.display(), error),
+            PsqlServerError::ServerCrashed { status, stderr_tail } =>
+                write!(f, "postgres exited unexpectedly ({}):\n{}", status, stderr_tail.join("\n")),
+            PsqlServerError::ExtensionNotAvailable { name, missing } => {
+                let missing: Vec<String> = missing.iter()
+                    .map(|path| path.display().to_string())
+                    .collect();
+                write!(f, "extension `{}` is not available, missing: {}", name, missing.join(", "))
+            },
+            PsqlServerError::NotBuiltWith(feature) =>
+                write!(f, "postgres was built without {} support", feature),
+            PsqlServerError::TlsConfig(message) =>
+                write!(f, "invalid TLS configuration: {}", message),
+            PsqlServerError::ConfigFile { path, error } =>
+                write!(f, "could not read config file {}: {}", path.display(), error),
+            PsqlServerError::MigrationFileFailed { file, stderr } =>
+                write!(f, "migration {} failed: {}", file.display(), stderr),
+            PsqlServerError::MigrationFailed(error) =>
+                write!(f, "migration failed: {}", error),
+            PsqlServerError::IrreversibleMigration(diff) =>
+                write!(f, "the down migration didn't restore the schema:\n{}", diff),
+            PsqlServerError::PsqlScriptFailed { file, code, stderr } => {
+                let code = code.map_or("a signal".to_owned(), |code| format!("code {}", code));
+                write!(f, "psql script {} exited with {}: {}", file.display(), code, stderr)
+            },
+            PsqlServerError::DryRun(commands) => {
+                write!(f, "dry run, would run:")?;
+                for command in commands {
+                    write!(f, "\n  {}", command.join(" "))?;
+                }
+                Ok(())
+            },
+            PsqlServerError::ManagedPostgresArg(arg) =>
+                write!(f, "postgres argument `{}` conflicts with one managed by testing-postgres", arg),
+            PsqlServerError::LogNotCaptured =>
+                write!(f, "the server's log isn't captured while stdio is inherited"),
+            #[cfg(feature = "tokio-postgres")]
+            PsqlServerError::Client(error) => match error.as_db_error() {
+                // the error itself only says "db error"
+                Some(db_error) => write!(f, "{}", db_error),
+                None => write!(f, "{}", error)
+            },
             PsqlServerError::IoError(error) =>
                 write!(f, "{}", error)
         }
@@ -79,104 +395,4275 @@ impl std::fmt::Display for PsqlServerError {
 
 impl PsqlServer {
     pub fn start() -> Result<PsqlServer, PsqlServerError> {
-        let postgres = which("postgres")
-            .map_err(|_| PsqlServerError::CouldNotFindPostgresCommand)?;
-        let initdb = which("initdb")
-            .map_err(|_| PsqlServerError::CouldNotFindInitDbCommand)?;
-        let createdb = which("createdb")
-            .map_err(|_| PsqlServerError::CouldNotFindCreateDbCommand)?;
-        let pg_isready = which("pg_isready")
-            .map_err(|_| PsqlServerError::CouldNotFindPgIsReadyCommand)?;
+        PsqlServerBuilder::new().start()
+    }
 
-        let base_dir = TempDir::new("postgresql")
-            .map_err(|e| PsqlServerError::IoError(e))?;
-        let base_path = base_dir.path();
-        let data_path = base_path.join("data").to_str()
-            .unwrap().to_owned();
-        let tmp_path = base_path.join("tmp").to_str()
-            .unwrap().to_owned();
-        fs::create_dir(&data_path)
-            .map_err(|e| PsqlServerError::IoError(e))?;
-        fs::create_dir(&tmp_path)
-            .map_err(|e| PsqlServerError::IoError(e))?;
+    pub fn start_from_basebackup(backup: &Path) -> Result<PsqlServer, PsqlServerError> {
+        PsqlServerBuilder::new().start_from_basebackup(backup)
+    }
 
-        let initdb_out = Command::new(&initdb)
-            .args(&["-D", &data_path, "--lc-messages=C",
-                    "-U", "postgres", "-A", "trust"])
-            .output()
-            .expect(&format!("failed to execute {}", initdb));
+    pub fn builder() -> PsqlServerBuilder {
+        PsqlServerBuilder::new()
+    }
 
-        if !initdb_out.status.success() {
-            return Err(PsqlServerError::InitDbFailed);
-        }
+    /// Starts a server with the default settings, runs `f` with it and
+    /// stops it again, see `PsqlServerBuilder::with`.
+    pub fn with<T, F: FnOnce(&PsqlServer) -> T>(f: F) -> Result<T, PsqlServerError> {
+        PsqlServerBuilder::new().with(f)
+    }
 
-        let port = get_unused_port()
-            .map_err(|e| PsqlServerError::IoError(e))?;
+    /// A handle to a server the crate didn't start, given its connection
+    /// parameters rather than a URL. Fails unless `database` accepts a
+    /// connection.
+    pub fn connect_external_parts(host: &str, port: u16, user: &str, password: Option<&str>, database: &str)
+                                  -> Result<ExternalServer, PsqlServerError> {
+        ExternalServer::connect_parts(host, port, user, password, database)
+    }
 
-        let mut process = Command::new(postgres)
-            .args(&["-p", &format!("{}", port),
-                    "-D", &data_path,
-                    "-k", &tmp_path,
-                    "-h", "127.0.0.1",
-                    "-F",
-                    "-c", "logging_collector=off"])
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .expect("failed to execute psql");
+    /// Runs `f` against a server with the default settings for each of
+    /// `versions` that is installed, see
+    /// `PsqlServerBuilder::for_each_installed_version`.
+    pub fn for_each_installed_version<F: Fn(&PsqlServer)>(versions: &[u32], f: F) -> Result<Vec<u32>, PsqlServerError> {
+        PsqlServerBuilder::new().for_each_installed_version(versions, f)
+    }
 
-        loop {
-            if let Some(_exit_code) = process.try_wait()
-                .map_err(|e| PsqlServerError::IoError(e))? {
-                    return Err(PsqlServerError::PostgresFailed);
-                }
-            let isready_out = Command::new(&pg_isready)
-                .args(&["-p", &format!("{}", port),
-                        "-h", "127.0.0.1",
-                        "-U", "postgres"])
-                .output()
-                .expect("failed to execute pg_isready");
-
-            if isready_out.status.success() {
-                break;
-            } else {
-                thread::sleep(Duration::from_millis(500))
+    /// Creates a template for `PsqlServerBuilder::template_data_dir` with
+    /// the default settings, see `PsqlServerBuilder::build_template`.
+    pub fn build_template(dir: &Path) -> Result<(), PsqlServerError> {
+        PsqlServerBuilder::new().build_template(dir)
+    }
+
+    /// Waits until the server at `host:port` accepts connections, polling
+    /// with `pg_isready`, e.g. for a server started by other means. Fails
+    /// with `StartupTimedOut` once `timeout` has passed.
+    pub fn wait_ready(port: u16, host: &str, user: &str, timeout: Duration) -> Result<(), PsqlServerError> {
+        let program = which("pg_isready")
+            .map_err(|_| PsqlServerError::CouldNotFindPgIsReadyCommand)?;
+        let deadline = Instant::now() + timeout;
+        while pg_isready(&program, port, host, user)? != Readiness::Accepting {
+            if Instant::now() >= deadline {
+                return Err(PsqlServerError::StartupTimedOut);
             }
+            thread::sleep(Duration::from_millis(500));
         }
+        Ok(())
+    }
 
-        let createdb_out = Command::new(createdb)
-            .args(&["-p", &format!("{}", port),
-                    "-h", "127.0.0.1",
-                    "-U", "postgres",
-                    "test"])
-            .output()
-            .expect("failed to execute createdb");
+    /// Starts `n` servers configured by `builder` in parallel, each with its
+    /// own port and data directory under one shared temporary directory. If
+    /// any of them fails to start, the others are shut down again and the
+    /// first error is returned.
+    pub fn start_many(n: usize, builder: &PsqlServerBuilder) -> Result<Vec<PsqlServer>, PsqlServerError> {
+        let parent_dir = TempDir::new(registry::PARENT_DIR_PREFIX.trim_end_matches('.'))
+            .and_then(|dir| registry::mark_owner(dir.path()).map(|()| dir))
+            .map_err(PsqlServerError::IoError)?;
+        let parent_dir = Arc::new(SharedDir(parent_dir.into_path()));
+        let results: Vec<Result<PsqlServer, PsqlServerError>> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..n)
+                .map(|_| scope.spawn(|| builder.start_with(DataSource::InitDb, Some(&parent_dir))))
+                .collect();
+            handles.into_iter()
+                .map(|handle| handle.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+                .collect()
+        });
+        // collecting into a Result drops (and so stops) the started servers
+        // when one failed
+        results.into_iter().collect()
+    }
 
-        if !createdb_out.status.success() {
-            return Err(PsqlServerError::CreateDbFailed);
+    /// Removes temporary directories left behind by servers whose process
+    /// was killed before it could clean up (e.g. a SIGKILLed test run),
+    /// returning the removed paths. Directories of servers that are still
+    /// running, in this or any other process, or that weren't created by
+    /// this crate, are left alone.
+    pub fn cleanup_orphans() -> Result<Vec<PathBuf>, PsqlServerError> {
+        let mut parents = vec![std::env::temp_dir()];
+        // the fallback socket directories always live in /tmp
+        if cfg!(unix) && !parents.contains(&PathBuf::from("/tmp")) {
+            parents.push(PathBuf::from("/tmp"));
         }
-
-        Ok(PsqlServer {
-            process,
-            base_dir: Some(base_dir),
-            port
-        })
+        let mut removed = Vec::new();
+        for parent in parents {
+            removed.extend(registry::cleanup_orphans(&parent)
+                           .map_err(PsqlServerError::IoError)?);
+        }
+        Ok(removed)
     }
 }
 
-impl fmt::Debug for PsqlServer {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "PsqlServer {{ port: {}, base_dir: {} }}",
-               self.port,
-               self.base_dir.as_ref().unwrap().path().display())
+/// Configures and starts a `PsqlServer`.
+#[derive(Debug)]
+pub struct PsqlServerBuilder {
+    config_params: Vec<(String, String)>,
+    /// Dropped again if postgres fails to start with them.
+    fallible_config_params: Vec<(String, String)>,
+    /// Written to `postgresql.auto.conf` rather than passed with `-c`.
+    auto_conf_params: Vec<(String, String)>,
+    preload_extensions: Vec<String>,
+    roles: Vec<String>,
+    database_owner: Option<String>,
+    use_template0: bool,
+    locale_provider: Option<LocaleProvider>,
+    icu_locale: Option<String>,
+    connection_limit: Option<i32>,
+    unique_database_name: bool,
+    psql_scripts: Vec<PathBuf>,
+    migrations: Vec<MigrationHook>,
+    extra_postgres_args: Vec<String>,
+    tls: Option<TlsConfig>,
+    self_signed_tls: bool,
+    config_file: Option<PathBuf>,
+    data_dir: Option<PathBuf>,
+    template_data_dir: Option<PathBuf>,
+    socket_dir: Option<PathBuf>,
+    listen_addresses: String,
+    connect_host: String,
+    tcp: bool,
+    synchronous: bool,
+    wait_for_stats: bool,
+    superuser: String,
+    auth_method: AuthMethod,
+    superuser_password: Option<String>,
+    initdb_no_sync: bool,
+    initdb_sync_method: Option<String>,
+    initdb_locale: Option<String>,
+    inherit_stdio: bool,
+    log_file: Option<PathBuf>,
+    nice: Option<i32>,
+    oom_score_adj: Option<i32>,
+    core_dumps: bool,
+    umask: Option<u32>,
+    allow_group_access: bool,
+    startup_timeout: Duration,
+    lifecycle: Lifecycle,
+    on_exit: Option<ExitHook>,
+    connection_options: Vec<(String, String)>,
+    major_version: Option<u32>,
+    cleanup: CleanupPolicy,
+    assert_clean: bool,
+    strict_clean: bool,
+    data_store: Option<StoreFactory>,
+    dry_run: bool
+}
+
+impl Default for PsqlServerBuilder {
+    fn default() -> PsqlServerBuilder {
+        PsqlServerBuilder {
+            // durability is rarely worth the cost for a throwaway server
+            config_params: vec![("fsync".to_owned(), "off".to_owned())],
+            fallible_config_params: Vec::new(),
+            auto_conf_params: Vec::new(),
+            preload_extensions: Vec::new(),
+            roles: Vec::new(),
+            database_owner: None,
+            use_template0: false,
+            locale_provider: None,
+            icu_locale: None,
+            connection_limit: None,
+            unique_database_name: false,
+            psql_scripts: Vec::new(),
+            migrations: Vec::new(),
+            extra_postgres_args: Vec::new(),
+            tls: None,
+            self_signed_tls: false,
+            config_file: None,
+            data_dir: None,
+            template_data_dir: None,
+            socket_dir: None,
+            listen_addresses: "127.0.0.1".to_owned(),
+            connect_host: "127.0.0.1".to_owned(),
+            tcp: true,
+            synchronous: false,
+            wait_for_stats: false,
+            superuser: "postgres".to_owned(),
+            auth_method: AuthMethod::Trust,
+            superuser_password: None,
+            initdb_no_sync: true,
+            initdb_sync_method: None,
+            initdb_locale: None,
+            inherit_stdio: false,
+            log_file: None,
+            nice: None,
+            oom_score_adj: None,
+            core_dumps: false,
+            umask: None,
+            allow_group_access: false,
+            startup_timeout: Duration::from_secs(30),
+            lifecycle: Lifecycle::Direct,
+            on_exit: None,
+            connection_options: Vec::new(),
+            major_version: None,
+            cleanup: CleanupPolicy::Always,
+            assert_clean: false,
+            strict_clean: false,
+            data_store: None,
+            dry_run: false
+        }
     }
 }
 
-impl Drop for PsqlServer {
-    fn drop(&mut self) {
-        self.process.kill()
-            .expect("failed to kill postgres");
-        self.process.wait().expect("....");
-        self.base_dir.take().unwrap().close().expect("failed to delete temp dir");
+impl PsqlServerBuilder {
+    pub fn new() -> PsqlServerBuilder {
+        PsqlServerBuilder::default()
+    }
+
+    /// Trades durability for speed: turns off `fsync`, `synchronous_commit`
+    /// and `full_page_writes`. Test data that doesn't survive a crash of the
+    /// machine is rarely a concern.
+    pub fn fast(self) -> PsqlServerBuilder {
+        self.fsync(false)
+            .synchronous_commit(false)
+            .full_page_writes(false)
+    }
+
+    /// Sets a server configuration parameter, passed to postgres as `-c name=value`.
+    /// Setting the same parameter again replaces the earlier value.
+    pub fn config_param(mut self, name: &str, value: &str) -> PsqlServerBuilder {
+        self.config_params.retain(|(n, _)| n != name);
+        self.config_params.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Like `config_param`, but if postgres fails to start, it is started
+    /// again without the fallible parameters (with a warning), for settings
+    /// the host may not support.
+    pub fn fallible_config_param(mut self, name: &str, value: &str) -> PsqlServerBuilder {
+        self.fallible_config_params.retain(|(n, _)| n != name);
+        self.fallible_config_params.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Writes `name = value` to the new cluster's `postgresql.auto.conf`,
+    /// as `ALTER SYSTEM` would, instead of passing it on the command line.
+    /// Tests can then `ALTER SYSTEM RESET` it, which a `config_param` would
+    /// override. An existing `data_dir` is left as it is.
+    pub fn auto_conf_param(mut self, name: &str, value: &str) -> PsqlServerBuilder {
+        self.auto_conf_params.retain(|(n, _)| n != name);
+        self.auto_conf_params.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Sets `huge_pages`. Postgres defaults to `HugePages::Try`, which falls
+    /// back to normal pages if the host has no huge pages configured, while
+    /// with `HugePages::On` it then fails to start.
+    pub fn huge_pages(self, huge_pages: HugePages) -> PsqlServerBuilder {
+        self.config_param("huge_pages", huge_pages.name())
+    }
+
+    /// Sets the default `statement_timeout` for all sessions.
+    pub fn statement_timeout(self, timeout: Duration) -> PsqlServerBuilder {
+        self.config_param("statement_timeout", &millis(timeout))
+    }
+
+    /// Sets the default `client_min_messages`, the lowest severity sent to
+    /// clients, e.g. `"debug1"` or `"warning"`. Postgres defaults to
+    /// `"notice"`; see `PsqlConnection::take_notices`.
+    pub fn client_min_messages(self, level: &str) -> PsqlServerBuilder {
+        self.config_param("client_min_messages", level)
+    }
+
+    /// Sets `log_min_messages`, the lowest severity written to the server
+    /// log, e.g. `"debug1"` or `"error"`. Postgres defaults to `"warning"`.
+    pub fn log_min_messages(self, level: &str) -> PsqlServerBuilder {
+        self.config_param("log_min_messages", level)
+    }
+
+    /// Sets `log_statement`: `"none"`, `"ddl"`, `"mod"` or `"all"`.
+    pub fn log_statement(self, statements: &str) -> PsqlServerBuilder {
+        self.config_param("log_statement", statements)
+    }
+
+    /// Sets `log_line_prefix`. While the log is captured (see
+    /// `inherit_stdio`) and no `config_file` is used, it defaults to
+    /// `DEFAULT_LOG_LINE_PREFIX`, so lines look the same across versions.
+    pub fn log_line_prefix(self, prefix: &str) -> PsqlServerBuilder {
+        self.config_param("log_line_prefix", prefix)
+    }
+
+    /// Sets `log_min_duration_statement`, logging every statement that runs at
+    /// least `duration` (`Duration::ZERO` logs all of them).
+    pub fn log_min_duration_statement(self, duration: Duration) -> PsqlServerBuilder {
+        self.config_param("log_min_duration_statement", &millis(duration))
+    }
+
+    /// Sets the default `lock_timeout` for all sessions.
+    pub fn lock_timeout(self, timeout: Duration) -> PsqlServerBuilder {
+        self.config_param("lock_timeout", &millis(timeout))
+    }
+
+    /// Sets the default `idle_in_transaction_session_timeout` for all sessions.
+    pub fn idle_in_transaction_session_timeout(self, timeout: Duration) -> PsqlServerBuilder {
+        self.config_param("idle_in_transaction_session_timeout", &millis(timeout))
+    }
+
+    /// Sets the default `idle_session_timeout` for all sessions, after which
+    /// the server closes connections idle outside a transaction (14+).
+    pub fn idle_session_timeout(self, timeout: Duration) -> PsqlServerBuilder {
+        self.config_param("idle_session_timeout", &millis(timeout))
+    }
+
+    /// Sets the isolation level new transactions get unless they ask for
+    /// another one.
+    pub fn default_transaction_isolation(self, level: IsolationLevel) -> PsqlServerBuilder {
+        self.config_param("default_transaction_isolation", level.name())
+    }
+
+    /// Sets `bytea_output`, `"hex"` or `"escape"`.
+    pub fn bytea_output(self, format: &str) -> PsqlServerBuilder {
+        self.config_param("bytea_output", format)
+    }
+
+    /// Sets the default `client_encoding`, e.g. `"UTF8"`.
+    pub fn client_encoding(self, encoding: &str) -> PsqlServerBuilder {
+        self.config_param("client_encoding", encoding)
+    }
+
+    /// Sets `min_wal_size`, e.g. `"1GB"`.
+    pub fn min_wal_size(self, size: &str) -> PsqlServerBuilder {
+        self.config_param("min_wal_size", size)
+    }
+
+    /// Sets `max_wal_size`, e.g. `"4GB"`. Raising it avoids frequent
+    /// checkpoints during bulk loads.
+    pub fn max_wal_size(self, size: &str) -> PsqlServerBuilder {
+        self.config_param("max_wal_size", size)
+    }
+
+    /// Sets `checkpoint_timeout`, the longest time between automatic
+    /// checkpoints (whole seconds, 30s to 1 day).
+    pub fn checkpoint_timeout(self, timeout: Duration) -> PsqlServerBuilder {
+        self.config_param("checkpoint_timeout", &format!("{}s", timeout.as_secs()))
+    }
+
+    /// Sets `checkpoint_completion_target`, the fraction of the interval
+    /// between checkpoints a checkpoint is spread over.
+    pub fn checkpoint_completion_target(self, target: f64) -> PsqlServerBuilder {
+        self.config_param("checkpoint_completion_target", &target.to_string())
+    }
+
+    /// Sets `work_mem`, e.g. `"64MB"`.
+    pub fn work_mem(self, size: &str) -> PsqlServerBuilder {
+        self.config_param("work_mem", size)
+    }
+
+    /// Sets `maintenance_work_mem`, e.g. `"256MB"`.
+    pub fn maintenance_work_mem(self, size: &str) -> PsqlServerBuilder {
+        self.config_param("maintenance_work_mem", size)
+    }
+
+    /// Sets `default_toast_compression` (PostgreSQL 14 or newer). Starting
+    /// fails with `NotBuiltWith` if `lz4` is asked for but postgres was
+    /// built without it.
+    pub fn default_toast_compression(self, compression: ToastCompression) -> PsqlServerBuilder {
+        self.config_param("default_toast_compression", compression.name())
+    }
+
+    /// Sets `temp_buffers`, the per-session memory for temporary tables,
+    /// e.g. `"32MB"` (a bare number is in 8kB blocks).
+    pub fn temp_buffers(self, size: &str) -> PsqlServerBuilder {
+        self.config_param("temp_buffers", size)
+    }
+
+    /// Sets `temp_file_limit`, the most temporary file space a process may
+    /// use, e.g. `"100MB"` (a bare number is in kB, `-1` means no limit).
+    /// Queries exceeding it fail instead of filling the disk.
+    pub fn temp_file_limit(self, size: &str) -> PsqlServerBuilder {
+        self.config_param("temp_file_limit", size)
+    }
+
+    /// Sets `effective_cache_size`, e.g. `"4GB"`.
+    pub fn effective_cache_size(self, size: &str) -> PsqlServerBuilder {
+        self.config_param("effective_cache_size", size)
+    }
+
+    /// Sets `max_prepared_transactions`, which has to be above 0 for
+    /// `PREPARE TRANSACTION` to work and can only change at startup.
+    pub fn max_prepared_transactions(self, count: u32) -> PsqlServerBuilder {
+        self.config_param("max_prepared_transactions", &count.to_string())
+    }
+
+    /// Sets `max_locks_per_transaction` (64 by default), which can only
+    /// change at startup. Transactions touching many tables or partitions
+    /// need it raised to avoid "out of shared memory" errors.
+    pub fn max_locks_per_transaction(self, count: u32) -> PsqlServerBuilder {
+        self.config_param("max_locks_per_transaction", &count.to_string())
+    }
+
+    /// Sets `max_worker_processes`, the limit on background workers
+    /// including parallel query workers. Only changes at startup.
+    pub fn max_worker_processes(self, count: u32) -> PsqlServerBuilder {
+        self.config_param("max_worker_processes", &count.to_string())
+    }
+
+    /// Sets `max_parallel_workers`, how many workers parallel queries may
+    /// use at once across the server.
+    pub fn max_parallel_workers(self, count: u32) -> PsqlServerBuilder {
+        self.config_param("max_parallel_workers", &count.to_string())
+    }
+
+    /// Sets `max_parallel_workers_per_gather`; 0 forbids parallel plans.
+    pub fn max_parallel_workers_per_gather(self, count: u32) -> PsqlServerBuilder {
+        self.config_param("max_parallel_workers_per_gather", &count.to_string())
+    }
+
+    /// Sets `cluster_name`, which shows up in `ps` output and makes it easier
+    /// to tell concurrent servers apart. Defaults to a name derived from the
+    /// server's temporary directory.
+    pub fn cluster_name(self, name: &str) -> PsqlServerBuilder {
+        self.config_param("cluster_name", name)
+    }
+
+    /// Turns `fsync` on or off. Defaults to off.
+    pub fn fsync(self, enabled: bool) -> PsqlServerBuilder {
+        self.config_param("fsync", on_off(enabled))
+    }
+
+    /// Turns `jit` on or off (PostgreSQL 11 or newer), e.g. off so JIT
+    /// compilation doesn't make `EXPLAIN ANALYZE` timings vary. Left at the
+    /// server's default unless set.
+    pub fn jit(self, enabled: bool) -> PsqlServerBuilder {
+        self.config_param("jit", on_off(enabled))
+    }
+
+    /// Turns `row_security` on or off. With it off, queries that row-level
+    /// security would filter fail instead, except for roles bypassing it.
+    pub fn row_security(self, enabled: bool) -> PsqlServerBuilder {
+        self.config_param("row_security", on_off(enabled))
+    }
+
+    /// Turns `standard_conforming_strings` on or off. With it off,
+    /// backslashes in ordinary string literals are escapes.
+    pub fn standard_conforming_strings(self, enabled: bool) -> PsqlServerBuilder {
+        self.config_param("standard_conforming_strings", on_off(enabled))
+    }
+
+    /// Turns `escape_string_warning` on or off, the warning about
+    /// backslashes in ordinary string literals.
+    pub fn escape_string_warning(self, enabled: bool) -> PsqlServerBuilder {
+        self.config_param("escape_string_warning", on_off(enabled))
+    }
+
+    /// Sets `backslash_quote`, whether `\'` may stand for a quote in a
+    /// string literal.
+    pub fn backslash_quote(self, backslash_quote: BackslashQuote) -> PsqlServerBuilder {
+        self.config_param("backslash_quote", backslash_quote.name())
+    }
+
+    /// Turns `synchronous_commit` on or off.
+    pub fn synchronous_commit(self, enabled: bool) -> PsqlServerBuilder {
+        self.config_param("synchronous_commit", on_off(enabled))
+    }
+
+    /// Turns `full_page_writes` on or off.
+    pub fn full_page_writes(self, enabled: bool) -> PsqlServerBuilder {
+        self.config_param("full_page_writes", on_off(enabled))
+    }
+
+    /// Turns the autovacuum launcher on or off.
+    pub fn autovacuum(self, enabled: bool) -> PsqlServerBuilder {
+        self.config_param("autovacuum", on_off(enabled))
+    }
+
+    /// Turns `track_io_timing` on or off.
+    pub fn track_io_timing(self, enabled: bool) -> PsqlServerBuilder {
+        self.config_param("track_io_timing", on_off(enabled))
+    }
+
+    /// Turns `track_counts` (table and index access statistics) on or off.
+    pub fn track_counts(self, enabled: bool) -> PsqlServerBuilder {
+        self.config_param("track_counts", on_off(enabled))
+    }
+
+    /// Sets `unix_socket_permissions` (e.g. `0o770`). Ignored with a
+    /// warning on windows, where no unix socket is used.
+    pub fn unix_socket_permissions(self, mode: u32) -> PsqlServerBuilder {
+        if cfg!(windows) {
+            eprintln!("testing-postgres: unix_socket_permissions is not supported on this platform, ignoring it");
+            return self;
+        }
+        self.config_param("unix_socket_permissions", &format!("0{:o}", mode))
+    }
+
+    /// Adds `name` to `shared_preload_libraries` and runs `CREATE EXTENSION`
+    /// for it in the `test` database once the server is up, for extensions
+    /// (e.g. TimescaleDB) that need both.
+    pub fn preload_extension(mut self, name: &str) -> PsqlServerBuilder {
+        self.preload_extensions.push(name.to_owned());
+        self
+    }
+
+    /// Preloads and creates `pg_stat_statements`, for reading with
+    /// `PsqlServer::top_statements`. Settings like `pg_stat_statements.max`
+    /// and `pg_stat_statements.track` can be passed with `config_param`.
+    pub fn pg_stat_statements(self) -> PsqlServerBuilder {
+        self.preload_extension("pg_stat_statements")
+    }
+
+    /// Runs `path` with `psql -f` against the default database once the
+    /// server is up (each time it starts), so scripts can use meta-commands
+    /// like `\i`, `\copy` and `\set`. Scripts run in the order they were
+    /// added and stop at the first error.
+    pub fn psql_script(mut self, path: PathBuf) -> PsqlServerBuilder {
+        self.psql_scripts.push(path);
+        self
+    }
+
+    /// Runs `migrate` once the server is up and set up (after any
+    /// `psql_script`s), e.g. to apply migrations with refinery or sqlx. It is
+    /// called for every server the builder starts, and an error it returns
+    /// fails the start with `PsqlServerError::MigrationFailed`.
+    pub fn migrate_with<F>(mut self, migrate: F) -> PsqlServerBuilder
+    where F: Fn(&ServerDescriptor) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+        + Send + Sync + 'static {
+        self.migrations.push(MigrationHook(Arc::new(migrate)));
+        self
+    }
+
+    /// Appends a raw argument to the postgres command line, for flags the
+    /// builder doesn't model. Arguments that would change the port, data
+    /// directory, socket directory or listen address the crate relies on are
+    /// rejected by `start` with `ManagedPostgresArg`.
+    pub fn postgres_arg(mut self, arg: &str) -> PsqlServerBuilder {
+        self.extra_postgres_args.push(arg.to_owned());
+        self
+    }
+
+    /// The name of the bootstrap superuser, used for initdb and for every
+    /// connection the crate makes. Defaults to `postgres`.
+    pub fn superuser(mut self, name: &str) -> PsqlServerBuilder {
+        self.superuser = name.to_owned();
+        self
+    }
+
+    /// Creates a login role `name` at startup unless it exists, with the
+    /// superuser's password if there is one.
+    pub fn role(mut self, name: &str) -> PsqlServerBuilder {
+        self.roles.push(name.to_owned());
+        self
+    }
+
+    /// Makes `role` the owner of the `test` database instead of the
+    /// superuser. The role is created like with `role` if it doesn't exist.
+    pub fn database_owner(mut self, role: &str) -> PsqlServerBuilder {
+        self.database_owner = Some(role.to_owned());
+        self
+    }
+
+    /// Creates the `test` database from `template0` rather than `template1`,
+    /// so it doesn't inherit anything added to `template1` and doesn't fail
+    /// while something is connected there.
+    pub fn use_template0(mut self, use_template0: bool) -> PsqlServerBuilder {
+        self.use_template0 = use_template0;
+        self
+    }
+
+    /// Creates the `test` database with the given locale provider
+    /// (PostgreSQL 15 or newer), from `template0` as the provider has to
+    /// differ from `template1`'s. ICU uses the root locale `und` unless
+    /// `icu_locale` is set. Starting fails with `NotBuiltWith` if ICU is
+    /// asked for but postgres was built without it.
+    pub fn locale_provider(mut self, provider: LocaleProvider) -> PsqlServerBuilder {
+        self.locale_provider = Some(provider);
+        self
+    }
+
+    /// Sets the ICU locale of the `test` database, e.g. `"und-u-ks-level2"`,
+    /// implying `locale_provider(LocaleProvider::Icu)`.
+    pub fn icu_locale(mut self, locale: &str) -> PsqlServerBuilder {
+        self.icu_locale = Some(locale.to_owned());
+        self
+    }
+
+    /// Sets the `CONNECTION LIMIT` of the `test` database, so connection
+    /// number `limit + 1` is refused with SQLSTATE 53300. Superusers are
+    /// exempt, so connect as a `role` to hit it.
+    pub fn connection_limit(mut self, limit: i32) -> PsqlServerBuilder {
+        self.connection_limit = Some(limit);
+        self
+    }
+
+    /// Names the default database `test_<random suffix>` instead of `test`,
+    /// so servers sharing a persistent `data_dir` don't collide. There the
+    /// database is created even if the cluster already exists, and dropped
+    /// again when the server is dropped. `connection_string` and the like
+    /// report the generated name.
+    pub fn unique_database_name(mut self, unique: bool) -> PsqlServerBuilder {
+        self.unique_database_name = unique;
+        self
+    }
+
+    /// How clients must authenticate, see `AuthMethod`. Defaults to
+    /// `AuthMethod::Trust`.
+    pub fn auth_method(mut self, method: AuthMethod) -> PsqlServerBuilder {
+        self.auth_method = method;
+        self
+    }
+
+    /// The superuser's password. With `None` (the default) and an auth method
+    /// that needs a password, a random one is generated, see
+    /// `PsqlServer::password`.
+    pub fn superuser_password(mut self, password: Option<&str>) -> PsqlServerBuilder {
+        self.superuser_password = password.map(|p| p.to_owned());
+        self
+    }
+
+    /// Runs initdb with `--no-sync`, skipping the final flush of the new
+    /// cluster to disk. That flush is most of initdb's time on slow disks
+    /// (seconds), while on tmpfs or a fast SSD it saves around 10%.
+    /// Defaults to true.
+    pub fn initdb_no_sync(mut self, no_sync: bool) -> PsqlServerBuilder {
+        self.initdb_no_sync = no_sync;
+        self
+    }
+
+    /// Runs initdb with `LC_ALL` set to `locale` (e.g. `"en_US.UTF-8"`),
+    /// which becomes the cluster's default locale. Defaults to `C` with
+    /// UTF-8 encoding, which works everywhere, unlike inheriting a `LANG`
+    /// naming a locale that minimal containers haven't generated.
+    pub fn initdb_locale_env(mut self, locale: &str) -> PsqlServerBuilder {
+        self.initdb_locale = Some(locale.to_owned());
+        self
+    }
+
+    /// How initdb flushes the cluster when `initdb_no_sync` is off, e.g.
+    /// `"syncfs"`. Only passed on PostgreSQL 17 and newer.
+    pub fn initdb_sync_method(mut self, method: &str) -> PsqlServerBuilder {
+        self.initdb_sync_method = Some(method.to_owned());
+        self
+    }
+
+    /// Enables TLS using the given certificates, see `TlsConfig`.
+    pub fn tls(mut self, tls: TlsConfig) -> PsqlServerBuilder {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Enables TLS with a freshly generated self-signed certificate for
+    /// `localhost` and `127.0.0.1`. `connection_string` and `env_vars` then
+    /// ask for `sslmode=require`.
+    #[cfg(feature = "tls")]
+    pub fn tls_self_signed(mut self) -> PsqlServerBuilder {
+        self.self_signed_tls = true;
+        self
+    }
+
+    /// Uses `path` as the server's `postgresql.conf`, e.g. to reproduce a
+    /// production configuration. The port, listen address and socket
+    /// directory are still chosen by the crate, and `config_param`s override
+    /// settings from the file.
+    pub fn config_file(mut self, path: PathBuf) -> PsqlServerBuilder {
+        self.config_file = Some(path);
+        self
+    }
+
+    /// Keeps the cluster in `path` instead of a temporary directory, so it
+    /// survives the server. If `path` already holds a cluster, `start` reuses
+    /// it (without running initdb or creating the `test` database again),
+    /// otherwise one is initialised there. See `PsqlServer::was_initialized`.
+    pub fn data_dir(mut self, path: PathBuf) -> PsqlServerBuilder {
+        self.data_dir = Some(path);
+        self
+    }
+
+    /// Copies the cluster in `path` (see `build_template`) instead of running
+    /// initdb, which is much faster. Its `PG_VERSION` must match the server
+    /// binary's. A template built with password authentication needs the
+    /// same `superuser_password` here, as the password is baked into it.
+    pub fn template_data_dir(mut self, path: PathBuf) -> PsqlServerBuilder {
+        self.template_data_dir = Some(path);
+        self
+    }
+
+    /// Runs initdb into `dir` with this builder's settings, creating a
+    /// template for `template_data_dir`. `dir` must not exist or be empty.
+    pub fn build_template(&self, dir: &Path) -> Result<(), PsqlServerError> {
+        let bin_dir = match self.major_version {
+            Some(major) => Some(find_bin_dir(major)?),
+            None => None
+        };
+        let postgres = find_tool("postgres", bin_dir.as_deref())
+            .map_err(|_| PsqlServerError::CouldNotFindPostgresCommand)?;
+        let initdb = find_tool("initdb", bin_dir.as_deref())
+            .map_err(|_| PsqlServerError::CouldNotFindInitDbCommand)?;
+        let version_num = binary_version_num(&postgres)?;
+        self.check_version(version_num)?;
+        let pwfile_dir = TempDir::new("pwfile")
+            .map_err(PsqlServerError::IoError)?;
+        self.run_initdb(&initdb, dir.to_str().unwrap(), &pwfile_dir.path().join("pwfile"),
+                        self.superuser_password.as_deref(), version_num)
+    }
+
+    /// Puts the unix socket in `path` instead of the server's temporary
+    /// directory, for systems where that can't hold sockets. The directory
+    /// must exist and be writable.
+    pub fn socket_dir(mut self, path: PathBuf) -> PsqlServerBuilder {
+        self.socket_dir = Some(path);
+        self
+    }
+
+    /// Sets the addresses postgres listens on (`listen_addresses`), e.g.
+    /// `"*"` to be reachable from a container network. Defaults to
+    /// `127.0.0.1`, which must stay reachable, as the crate's own tools
+    /// connect there. Other clients also need a `pg_hba.conf` letting them
+    /// in, as initdb's only trusts local connections.
+    pub fn listen_addresses(mut self, addresses: &str) -> PsqlServerBuilder {
+        self.listen_addresses = addresses.to_owned();
+        self
+    }
+
+    /// Sets the host `connection_string`, `env_vars` and `endpoint` report,
+    /// for when clients reach the server under another name than the one
+    /// it binds (see `listen_addresses`). Defaults to `127.0.0.1`.
+    pub fn connect_host(mut self, host: &str) -> PsqlServerBuilder {
+        self.connect_host = host.to_owned();
+        self
+    }
+
+    /// With `false`, postgres listens on its unix socket only, without
+    /// binding any TCP port. `connection_string`, `env_vars` and `endpoint`
+    /// then point at the socket directory. Ignored with a warning on
+    /// windows, where there is no unix socket.
+    pub fn tcp(mut self, tcp: bool) -> PsqlServerBuilder {
+        if cfg!(windows) && !tcp {
+            eprintln!("testing-postgres: tcp(false) is not supported on this platform, ignoring it");
+            return self;
+        }
+        self.tcp = tcp;
+        self
+    }
+
+    /// Makes the standbys this builder starts (see `start_standby` and
+    /// `ReplicaSet`) synchronous: each is added to the primary's
+    /// `synchronous_standby_names` under its `cluster_name`, and
+    /// `start_standby` returns once the primary has picked it up. Commits
+    /// on the primary (with the default `synchronous_commit`) then wait
+    /// until the first of them has flushed them.
+    pub fn synchronous(mut self, synchronous: bool) -> PsqlServerBuilder {
+        self.synchronous = synchronous;
+        self
+    }
+
+    /// Makes `start` wait (up to the `startup_timeout`) until the cumulative
+    /// statistics reflect a transaction run once the server is set up, so
+    /// tests reading `pg_stat_*` views right away don't see stale counts.
+    pub fn wait_for_stats(mut self, wait: bool) -> PsqlServerBuilder {
+        self.wait_for_stats = wait;
+        self
+    }
+
+    /// Lets postgres write straight to the parent's stdout/stderr instead
+    /// of piping its output, which is useful when debugging a server that
+    /// won't start. The crate then can't read the log: a server that fails
+    /// to start is reported as `PostgresFailed` or `StartupTimedOut` rather
+    /// than with its FATAL message, `startup_log` stays empty, `log_stream`
+    /// yields nothing and `wait_for_log` fails with `LogNotCaptured`.
+    pub fn inherit_stdio(mut self, inherit: bool) -> PsqlServerBuilder {
+        self.inherit_stdio = inherit;
+        self
+    }
+
+    /// Also appends the server's log to `path`, one line per message in
+    /// postgres' default `stderr` format, for tests that parse query logs
+    /// (see `log_statement`). Not written when stdio is inherited.
+    pub fn log_file(mut self, path: PathBuf) -> PsqlServerBuilder {
+        self.log_file = Some(path);
+        self
+    }
+
+    /// Runs postgres at the given niceness (unix only; ignored elsewhere), so
+    /// a busy database doesn't starve the tests driving it. Negative values
+    /// usually need elevated privileges.
+    pub fn nice(mut self, nice: i32) -> PsqlServerBuilder {
+        self.nice = Some(nice);
+        self
+    }
+
+    /// Sets the OOM score adjustment (-1000 to 1000) of postgres and its
+    /// backends (Linux only; ignored elsewhere), e.g. a high value so the
+    /// OOM killer picks the database over the test runner. Lowering it
+    /// usually needs elevated privileges.
+    pub fn oom_score_adj(mut self, adj: i32) -> PsqlServerBuilder {
+        self.oom_score_adj = Some(adj);
+        self
+    }
+
+    /// Raises the core file size limit of postgres and its backends as far
+    /// as the hard limit allows (unix only; ignored elsewhere), for
+    /// debugging crashing extensions. Where the core ends up is decided by
+    /// the host's `kernel.core_pattern`: with a plain pattern like `core`
+    /// it is written to the data directory, which `CleanupPolicy::OnSuccess`
+    /// keeps after a failed test, while a pattern piping to a handler such
+    /// as systemd-coredump sends it there instead.
+    pub fn enable_core_dumps(mut self, enabled: bool) -> PsqlServerBuilder {
+        self.core_dumps = enabled;
+        self
+    }
+
+    /// Runs initdb and postgres with the given umask (unix only; ignored
+    /// with a warning elsewhere). Note that initdb and postgres (11+) pick
+    /// the data directory's mode themselves, see `allow_group_access`.
+    pub fn umask(mut self, umask: u32) -> PsqlServerBuilder {
+        self.umask = Some(umask);
+        self
+    }
+
+    /// Runs initdb with `--allow-group-access` (PostgreSQL 11+), making the
+    /// data directory group readable.
+    pub fn allow_group_access(mut self, allow: bool) -> PsqlServerBuilder {
+        self.allow_group_access = allow;
+        self
+    }
+
+    /// How long to wait for postgres to accept connections before giving up
+    /// with `StartupTimedOut`. Defaults to 30 seconds.
+    pub fn startup_timeout(mut self, timeout: Duration) -> PsqlServerBuilder {
+        self.startup_timeout = timeout;
+        self
+    }
+
+    /// How postgres is started and stopped, see `Lifecycle`. Defaults to
+    /// `Lifecycle::Direct`.
+    pub fn lifecycle(mut self, lifecycle: Lifecycle) -> PsqlServerBuilder {
+        self.lifecycle = lifecycle;
+        self
+    }
+
+    /// Calls `on_exit` from a background thread when postgres exits without
+    /// being stopped, e.g. after a crash in an extension under test. Either
+    /// way, methods needing the server then fail with
+    /// `PsqlServerError::ServerCrashed`. Not supported with
+    /// `Lifecycle::PgCtl`, where postgres isn't a child process.
+    pub fn on_exit<F>(mut self, on_exit: F) -> PsqlServerBuilder
+    where F: Fn(ExitStatus) + Send + Sync + 'static {
+        self.on_exit = Some(ExitHook(Arc::new(on_exit)));
+        self
+    }
+
+    /// Adds a query parameter such as `connect_timeout` or `application_name`
+    /// to every connection string of the server. `sslmode` defaults to
+    /// `require` when TLS is enabled.
+    pub fn connection_option(mut self, key: &str, value: &str) -> PsqlServerBuilder {
+        self.connection_options.retain(|(k, _)| k != key);
+        self.connection_options.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Uses the binaries of a specific PostgreSQL major version (e.g. `16`)
+    /// when several are installed, failing with `VersionNotFound` if it
+    /// isn't. By default whatever is first on `PATH` is used.
+    pub fn major_version(mut self, major: u32) -> PsqlServerBuilder {
+        self.major_version = Some(major);
+        self
+    }
+
+    /// Creates each server's directory with `create` instead of as a
+    /// `TempDir` in the system's temporary directory, e.g. to put it on a
+    /// RAM disk. Servers of `PsqlServer::start_many` then don't share a
+    /// parent directory.
+    pub fn data_store<F>(mut self, create: F) -> PsqlServerBuilder
+    where F: Fn() -> std::io::Result<Box<dyn DataStore>> + Send + Sync + 'static {
+        self.data_store = Some(StoreFactory(Arc::new(create)));
+        self
+    }
+
+    /// When the server's temporary directory is deleted, see `CleanupPolicy`.
+    /// Defaults to `CleanupPolicy::Always`.
+    pub fn cleanup(mut self, cleanup: CleanupPolicy) -> PsqlServerBuilder {
+        self.cleanup = cleanup;
+        self
+    }
+
+    /// Checks for client connections still open when the server is dropped,
+    /// to catch connection and transaction leaks, and prints a warning if
+    /// there are any.
+    pub fn assert_clean_on_drop(mut self, assert_clean: bool) -> PsqlServerBuilder {
+        self.assert_clean = assert_clean;
+        self
+    }
+
+    /// Like `assert_clean_on_drop`, but panics instead of warning (unless
+    /// the thread is already panicking).
+    pub fn strict_clean_on_drop(mut self, strict: bool) -> PsqlServerBuilder {
+        self.strict_clean = strict;
+        self
+    }
+
+    /// Makes `start` stop short of running anything: it resolves the binaries
+    /// and fails with `PsqlServerError::DryRun` listing the commands it would
+    /// have run, with placeholders for the temporary paths and port.
+    pub fn dry_run(mut self, dry_run: bool) -> PsqlServerBuilder {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn start(&self) -> Result<PsqlServer, PsqlServerError> {
+        self.start_with(DataSource::InitDb, None)
+    }
+
+    /// Starts a server, runs `f` with it and stops it again before
+    /// returning what `f` returned. If `f` panics, the server is stopped
+    /// while the panic carries on, so the cleanup policy sees it as a
+    /// failure.
+    pub fn with<T, F: FnOnce(&PsqlServer) -> T>(&self, f: F) -> Result<T, PsqlServerError> {
+        let server = self.start()?;
+        match panic::catch_unwind(AssertUnwindSafe(|| f(&server))) {
+            Ok(value) => {
+                drop(server);
+                Ok(value)
+            }
+            // `server` is dropped while unwinding
+            Err(payload) => panic::resume_unwind(payload)
+        }
+    }
+
+    /// Like `with`, once for each of the major `versions` (e.g. `[14, 16]`)
+    /// that `major_version` finds installed, skipping the others with a
+    /// note on stderr. Returns the versions `f` ran against.
+    pub fn for_each_installed_version<F: Fn(&PsqlServer)>(mut self, versions: &[u32], f: F)
+                                                         -> Result<Vec<u32>, PsqlServerError> {
+        let mut ran = Vec::new();
+        for &major in versions {
+            if let Err(error) = find_bin_dir(major) {
+                eprintln!("testing-postgres: {}, skipping it", error);
+                continue;
+            }
+            self.major_version = Some(major);
+            self.with(&f)?;
+            ran.push(major);
+        }
+        Ok(ran)
+    }
+
+    /// Starts a server on a copy of a `pg_basebackup` (a directory, or a
+    /// plain format `.tar` archive) instead of a freshly initialised cluster.
+    ///
+    /// The backup's `pg_hba.conf` is replaced to trust local connections,
+    /// settings that would tie it to its original host (archiving,
+    /// replication, file locations) are overridden in `postgresql.auto.conf`,
+    /// and any `standby.signal`/`recovery.signal` is removed so the server
+    /// replays the WAL included in the backup and then comes up as a primary.
+    /// No `test` database is created.
+    pub fn start_from_basebackup(&self, backup: &Path) -> Result<PsqlServer, PsqlServerError> {
+        self.start_with(DataSource::BaseBackup(backup), None)
+    }
+
+    /// Starts a hot standby of `primary`, streaming from it. The standby is
+    /// cloned with `pg_basebackup`, so it has the primary's databases, roles
+    /// and password; settings like `max_connections` must be at least the
+    /// primary's. Preloaded extensions are not created, as the standby is
+    /// read-only.
+    pub fn start_standby(&self, primary: &PsqlServer) -> Result<PsqlServer, PsqlServerError> {
+        self.start_with(DataSource::Standby(primary), None)
+    }
+
+    /// Runs the cluster with `postgres --single` connected to `db`, for
+    /// testing offline maintenance, instead of starting a server. The
+    /// cluster is set up like for `start` (so `data_dir` and
+    /// `template_data_dir` apply), but no `test` database is created, and
+    /// options that need a running server, like extensions and scripts, are
+    /// ignored.
+    pub fn start_single_user(&self, db: &str) -> Result<SingleUserSession, PsqlServerError> {
+        let source = self.resolve_source(DataSource::InitDb);
+        let bin_dir = match self.major_version {
+            Some(major) => Some(find_bin_dir(major)?),
+            None => None
+        };
+        let postgres = find_tool("postgres", bin_dir.as_deref())
+            .map_err(|_| PsqlServerError::CouldNotFindPostgresCommand)?;
+        let initdb = find_tool("initdb", bin_dir.as_deref())
+            .map_err(|_| PsqlServerError::CouldNotFindInitDbCommand)?;
+        let version_num = binary_version_num(&postgres)?;
+        self.check_version(version_num)?;
+        let password = match &self.superuser_password {
+            Some(password) => Some(password.clone()),
+            None if self.auth_method.needs_password() => Some(random_password()),
+            None => None
+        };
+
+        let base_dir = self.create_base_dir(None)?;
+        let prepared = self.prepare_dirs(source, base_dir.path(), &initdb, password.as_deref(),
+                                         version_num, None);
+        let data_path = match prepared {
+            Ok(prepared) => prepared.data_path,
+            Err(e) => {
+                if self.cleanup != CleanupPolicy::Always {
+                    keep_dir(base_dir);
+                }
+                return Err(e);
+            }
+        };
+        let mut args: Vec<String> = vec!["--single".to_owned(), "-j".to_owned(),
+                                         "-D".to_owned(), data_path];
+        for (name, value) in self.effective_config_params() {
+            args.push("-c".to_owned());
+            args.push(format!("{}={}", name, value));
+        }
+        args.push(db.to_owned());
+        SingleUserSession::spawn(&postgres, args, base_dir, self.cleanup)
+    }
+
+    /// Uses an existing `data_dir` cluster (or a `template_data_dir`) instead
+    /// of running initdb, if there is one.
+    fn resolve_source<'a>(&'a self, source: DataSource<'a>) -> DataSource<'a> {
+        match (source, &self.data_dir, &self.template_data_dir) {
+            (DataSource::InitDb, Some(data_dir), _) if data_dir.join("PG_VERSION").exists() =>
+                DataSource::Existing(data_dir),
+            (DataSource::InitDb, _, Some(template)) => DataSource::Template(template),
+            (source, _, _) => source
+        }
+    }
+
+    fn create_base_dir(&self, parent_dir: Option<&Arc<SharedDir>>) -> Result<Box<dyn DataStore>, PsqlServerError> {
+        match (&self.data_store, parent_dir) {
+            (Some(create), _) => create.0(),
+            (None, Some(parent_dir)) => TempDir::new_in(&parent_dir.0, registry::BASE_DIR_PREFIX.trim_end_matches('.'))
+                .map(|dir| Box::new(dir) as Box<dyn DataStore>),
+            (None, None) => TempDir::new(registry::BASE_DIR_PREFIX.trim_end_matches('.'))
+                .map(|dir| Box::new(dir) as Box<dyn DataStore>)
+        }.and_then(|dir| registry::mark_owner(dir.path()).map(|()| dir))
+            .map_err(PsqlServerError::IoError)
+    }
+
+    fn start_with(&self, source: DataSource, parent_dir: Option<&Arc<SharedDir>>)
+                  -> Result<PsqlServer, PsqlServerError> {
+        let source = self.resolve_source(source);
+        let bin_dir = match self.major_version {
+            Some(major) => Some(find_bin_dir(major)?),
+            None => None
+        };
+        let postgres = find_tool("postgres", bin_dir.as_deref())
+            .map_err(|_| PsqlServerError::CouldNotFindPostgresCommand)?;
+        let initdb = find_tool("initdb", bin_dir.as_deref())
+            .map_err(|_| PsqlServerError::CouldNotFindInitDbCommand)?;
+        let createdb = find_tool("createdb", bin_dir.as_deref())
+            .map_err(|_| PsqlServerError::CouldNotFindCreateDbCommand)?;
+        let pg_isready = find_tool("pg_isready", bin_dir.as_deref())
+            .map_err(|_| PsqlServerError::CouldNotFindPgIsReadyCommand)?;
+        let pg_ctl = match self.lifecycle {
+            Lifecycle::Direct => None,
+            Lifecycle::PgCtl => Some(find_tool("pg_ctl", bin_dir.as_deref())
+                .map_err(|_| PsqlServerError::CouldNotFindPgCtlCommand)?)
+        };
+
+        let version_num = binary_version_num(&postgres)?;
+        self.check_version(version_num)?;
+
+        let config_file = match &self.config_file {
+            Some(path) => Some(fs::read_to_string(path)
+                .map_err(|error| PsqlServerError::ConfigFile { path: path.clone(), error })?),
+            None => None
+        };
+
+        for extension in &self.preload_extensions {
+            check_extension_files(&postgres, extension)?;
+        }
+        let lz4 = self.config_params.iter()
+            .any(|(name, value)| name == "default_toast_compression" && value == "lz4");
+        if lz4 {
+            check_built_with(&postgres, "lz4", version_num)?;
+        }
+        if self.effective_locale_provider() == Some(LocaleProvider::Icu) {
+            check_built_with(&postgres, "icu", version_num)?;
+        }
+
+        let password = match (&self.superuser_password, source) {
+            (_, DataSource::Standby(primary)) => primary.password.clone(),
+            (password, DataSource::Template(_)) => password.clone(),
+            (Some(password), _) => Some(password.clone()),
+            (None, _) if self.auth_method.needs_password() => Some(random_password()),
+            (None, _) => None
+        };
+
+        if let Some(arg) = find_managed_postgres_arg(&self.extra_postgres_args) {
+            return Err(PsqlServerError::ManagedPostgresArg(arg.clone()));
+        }
+
+        if let Some(socket_dir) = &self.socket_dir {
+            check_socket_dir(socket_dir)?;
+        }
+
+        let database = match source {
+            DataSource::BaseBackup(_) => "postgres".to_owned(),
+            DataSource::Standby(primary) => primary.database.clone(),
+            _ if self.unique_database_name => format!("test_{}", random_password()[..8].to_lowercase()),
+            _ => "test".to_owned()
+        };
+        // with a unique name, even an existing cluster needs the database
+        let create_database = match source {
+            DataSource::InitDb | DataSource::Template(_) => true,
+            DataSource::Existing(_) => self.unique_database_name,
+            _ => false
+        };
+
+        if self.dry_run {
+            let mut commands = Vec::new();
+            if let DataSource::InitDb = source {
+                let pwfile = password.as_ref().map(|_| "$PWFILE");
+                commands.push(command_line(&initdb, self.initdb_args("$DATA_DIR", pwfile, version_num)));
+            }
+            commands.push(command_line(&postgres, self.postgres_args(
+                "$PORT", "$DATA_DIR", "$SOCKET_DIR", "$CLUSTER_NAME")));
+            if create_database {
+                let host = if self.tcp { "127.0.0.1" } else { "$SOCKET_DIR" };
+                commands.push(command_line(&createdb, self.createdb_args(host, "$PORT", &database)));
+            }
+            return Err(PsqlServerError::DryRun(commands));
+        }
+
+        // binary discovery above is excluded from the timings
+        let started = Instant::now();
+        let base_dir = self.create_base_dir(parent_dir)?;
+        let base_path = base_dir.path();
+        let prepared = self.prepare_dirs(source, base_path, &initdb, password.as_deref(),
+                                         version_num, config_file);
+        let PreparedDirs { data_path, socket_path, socket_dir, port, reservation } = match prepared {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                if self.cleanup != CleanupPolicy::Always {
+                    keep_dir(base_dir);
+                }
+                return Err(e);
+            }
+        };
+        let initialized = Instant::now();
+
+        let postgres = PostgresCommand {
+            args: self.postgres_args(&format!("{}", port), &data_path, &socket_path,
+                                     &default_cluster_name(base_path)),
+            program: postgres,
+            pg_ctl,
+            data_dir: data_path.clone(),
+            startup_timeout: self.startup_timeout,
+            inherit_stdio: self.inherit_stdio,
+            nice: self.nice,
+            oom_score_adj: self.oom_score_adj,
+            core_dumps: self.core_dumps,
+            umask: self.umask
+        };
+        // From here on the server owns the process and its directories, so
+        // returning early (or panicking) still kills postgres and cleans up.
+        let log = Arc::new(match &self.log_file {
+            Some(path) => ServerLog::with_file(path).map_err(PsqlServerError::IoError)?,
+            None => ServerLog::default()
+        });
+        // Dropped in the parent rather than in a `pre_exec` hook: a port is
+        // only free once every descriptor of the socket is closed, and the
+        // child's copy is close-on-exec anyway, so it is the parent's copy
+        // that matters. That can't be closed between fork and exec, as std
+        // has no hook there and `spawn` returns only after the exec, by
+        // which time postgres may already be binding. This leaves the fork,
+        // exec and postgres' own startup (a few milliseconds) for another
+        // process to take the port, which then fails with `PortInUse`.
+        drop(reservation);
+        let process = postgres.spawn(&log).map_err(PsqlServerError::IoError)?;
+        let mut server = PsqlServer {
+            process: Arc::new(Mutex::new(Some(process))),
+            base_dir: Some(base_dir),
+            parent_dir: parent_dir.cloned(),
+            socket_dir,
+            socket_path: PathBuf::from(&socket_path),
+            data_dir: PathBuf::from(data_path),
+            postgres,
+            pg_isready,
+            superuser: match source {
+                DataSource::Standby(primary) => primary.superuser.clone(),
+                _ => self.superuser.clone()
+            },
+            password,
+            auth_method: self.auth_method,
+            connection_options: self.effective_connection_options(),
+            connect_host: if self.tcp { self.connect_host.clone() } else { socket_path.clone() },
+            local_host: if self.tcp { "127.0.0.1".to_owned() } else { socket_path.clone() },
+            database,
+            drop_unique_database_on_drop: create_database && self.unique_database_name && self.data_dir.is_some(),
+            snapshot_count: 0,
+            version_num,
+            timings: StartupTimings::default(),
+            bin_dir,
+            psql: OnceLock::new(),
+            log,
+            log_path: self.log_file.clone(),
+            startup_log: Vec::new(),
+            ran_initdb: matches!(source, DataSource::InitDb),
+            cleanup: self.cleanup,
+            assert_clean: self.assert_clean || self.strict_clean,
+            strict_clean: self.strict_clean,
+            started: false,
+            paused: AtomicBool::new(false),
+            repro_script: String::new(),
+            port
+        };
+        registry::register(server.base_dir.as_ref().unwrap().path());
+        match server.wait_until_ready() {
+            Err(error @ (PsqlServerError::PostgresFatal(_) | PsqlServerError::PostgresFailed))
+                if !self.fallible_config_params.is_empty() => {
+                    let names: Vec<&str> = self.fallible_config_params.iter()
+                        .map(|(name, _)| name.as_str())
+                        .collect();
+                    eprintln!("testing-postgres: {}, starting again without {}", error, names.join(", "));
+                    for name in names {
+                        server.postgres.remove_param(name);
+                    }
+                    server.restart()?;
+                },
+            ready => ready?
+        }
+        let ready = Instant::now();
+        if let (Some(on_exit), None) = (&self.on_exit, &server.postgres.pg_ctl) {
+            watch_for_exit(Arc::downgrade(&server.process), on_exit.clone());
+        }
+
+        if let (DataSource::Standby(primary), true) = (source, self.synchronous) {
+            let name = default_cluster_name(server.base_dir.as_ref().unwrap().path());
+            primary.add_synchronous_standby(&name, self.startup_timeout)?;
+        }
+
+        if !matches!(source, DataSource::Standby(_)) {
+            for role in self.roles.iter().chain(&self.database_owner) {
+                server.create_role(role)?;
+            }
+        }
+
+        // a `Template` may have been built with the database in it
+        let create_database = create_database && !(matches!(source, DataSource::Template(_))
+            && server.query_value("postgres", &format!(
+                "SELECT count(*) FROM pg_database WHERE datname = {}", quote_literal(&server.database)))?
+                .trim() != "0");
+        if create_database {
+            self.create_test_database(&server, &createdb)?;
+        }
+        let created = Instant::now();
+        server.repro_script = self.repro_script(&server, source, &initdb, &createdb, create_database);
+
+        self.set_up(&server, source)?;
+        if self.wait_for_stats {
+            server.wait_for_stats(self.startup_timeout)?;
+        }
+
+        server.timings = StartupTimings {
+            init: initialized - started,
+            ready: ready - initialized,
+            create_database: created - ready,
+            setup: created.elapsed()
+        };
+        server.startup_log = server.log.lines(MAX_STARTUP_LOG_LINES);
+        server.started = true;
+        Ok(server)
+    }
+
+    /// A shell script running the same commands `start` ran for `server`,
+    /// with its directories and port in variables.
+    fn repro_script(&self, server: &PsqlServer, source: DataSource, initdb: &str, createdb: &str,
+                    create_database: bool) -> String {
+        let base_path = server.base_dir.as_ref().unwrap().path();
+        let data_dir = match source {
+            DataSource::Existing(data_dir) => script_word(&data_dir.to_string_lossy()),
+            _ => "$BASE_DIR/data".to_owned()
+        };
+        let host = if self.tcp { "127.0.0.1" } else { "$SOCKET_DIR" };
+        let mut lines = vec![
+            "BASE_DIR=${BASE_DIR:-/tmp/testing-postgres-repro}".to_owned(),
+            format!("DATA_DIR=${{DATA_DIR:-{}}}", data_dir),
+            "SOCKET_DIR=${SOCKET_DIR:-$BASE_DIR/sockets}".to_owned(),
+            format!("PORT=${{PORT:-{}}}", server.port),
+            format!("CLUSTER_NAME=${{CLUSTER_NAME:-{}}}", default_cluster_name(base_path)),
+            "PWFILE=$BASE_DIR/pwfile".to_owned(),
+            "export PGSSLMODE=disable".to_owned()];
+        if let Some(password) = &server.password {
+            lines.push(format!("export PGPASSWORD={}", script_word(password)));
+        }
+        lines.push("mkdir -p \"$BASE_DIR\" \"$SOCKET_DIR\"".to_owned());
+        match source {
+            DataSource::InitDb => {
+                lines.push("mkdir -p \"$DATA_DIR\"".to_owned());
+                if let Some(password) = &server.password {
+                    lines.push(format!("printf '%s' {} > \"$PWFILE\"", script_word(password)));
+                }
+                let pwfile = server.password.as_ref().map(|_| "$PWFILE");
+                lines.push(format!("LC_ALL={} {}",
+                                   script_word(self.initdb_locale.as_deref().unwrap_or("C")),
+                                   script_command(&command_line(initdb, self.initdb_args(
+                                       "$DATA_DIR", pwfile, server.version_num)))));
+            },
+            DataSource::Template(template) =>
+                lines.push(format!("cp -R {} \"$DATA_DIR\"", script_word(&template.to_string_lossy()))),
+            DataSource::BaseBackup(_) | DataSource::Standby(_) =>
+                lines.push("# the data directory was copied from a base backup, which isn't reproduced".to_owned()),
+            DataSource::Existing(_) => ()
+        }
+        if !matches!(source, DataSource::Existing(_) | DataSource::Standby(_)) {
+            for (name, value) in &self.auto_conf_params {
+                lines.push(format!("echo {} >> \"$DATA_DIR/postgresql.auto.conf\"",
+                                   script_word(&format!("{} = {}", name, quote_literal(value)))));
+            }
+        }
+        lines.push(format!("{} &", script_command(&command_line(&server.postgres.program, self.postgres_args(
+            "$PORT", "$DATA_DIR", "$SOCKET_DIR", "$CLUSTER_NAME")))));
+        lines.push("POSTGRES_PID=$!".to_owned());
+        lines.push(format!("until {}; do sleep 1; done", script_command(&command_line(&server.pg_isready, vec![
+            "-q".to_owned(), "-h".to_owned(), host.to_owned(), "-p".to_owned(), "$PORT".to_owned()]))));
+
+        let psql = find_tool("psql", server.bin_dir.as_deref()).unwrap_or_else(|_| "psql".to_owned());
+        let psql = |db: &str, args: &[&str]| {
+            let mut command: Vec<String> = ["-X", "-q", "-v", "ON_ERROR_STOP=1", "-p", "$PORT", "-h", host,
+                                            "-U", &server.superuser, "-d", db]
+                .iter().chain(args).map(|arg| arg.to_string()).collect();
+            command.insert(0, psql.clone());
+            script_command(&command)
+        };
+        if !matches!(source, DataSource::Standby(_)) {
+            for role in self.roles.iter().chain(&self.database_owner) {
+                let password = match &server.password {
+                    Some(password) => format!(" PASSWORD {}", quote_literal(password)),
+                    None => String::new()
+                };
+                lines.push(format!("{} || true", psql("postgres", &[
+                    "-c", &format!("CREATE ROLE {} LOGIN{}", quote_ident(role), password)])));
+            }
+        }
+        if create_database {
+            lines.push(script_command(&command_line(createdb, self.createdb_args(host, "$PORT", &server.database))));
+            if let Some(limit) = self.connection_limit {
+                lines.push(psql("postgres", &["-c", &format!(
+                    "ALTER DATABASE {} CONNECTION LIMIT {}", quote_ident(&server.database), limit)]));
+            }
+        }
+        if !matches!(source, DataSource::Standby(_)) {
+            for extension in &self.preload_extensions {
+                lines.push(psql(&server.database, &[
+                    "-c", &format!("CREATE EXTENSION IF NOT EXISTS {}", quote_ident(extension))]));
+            }
+        }
+        for script in &self.psql_scripts {
+            lines.push(psql(&server.database, &["-f", &script.to_string_lossy()]));
+        }
+        if !self.migrations.is_empty() && !matches!(source, DataSource::Standby(_)) {
+            lines.push(format!("# {} migration hook(s) ran here, which can't be exported", self.migrations.len()));
+        }
+        lines.push("wait \"$POSTGRES_PID\"".to_owned());
+        lines.join("\n")
+    }
+
+    fn create_test_database(&self, server: &PsqlServer, createdb: &str) -> Result<(), PsqlServerError> {
+        let createdb_out = server.tool_command(createdb)
+            .args(self.createdb_args(&server.local_host, &format!("{}", server.port), &server.database))
+            .output()
+            .expect("failed to execute createdb");
+
+        if !createdb_out.status.success() {
+            return Err(PsqlServerError::CreateDbFailed);
+        }
+        // createdb has no option for it
+        if let Some(limit) = self.connection_limit {
+            server.connect("postgres")?.execute(&format!(
+                "ALTER DATABASE {} CONNECTION LIMIT {}", quote_ident(&server.database), limit))?;
+        }
+        Ok(())
+    }
+
+    /// Creates the preloaded extensions, then runs the psql scripts and
+    /// migrations.
+    fn set_up(&self, server: &PsqlServer, source: DataSource) -> Result<(), PsqlServerError> {
+        if !self.preload_extensions.is_empty() && !matches!(source, DataSource::Standby(_)) {
+            let mut connection = server.connect(&server.database)?;
+            for extension in &self.preload_extensions {
+                connection.execute(&format!("CREATE EXTENSION IF NOT EXISTS {}", quote_ident(extension)))?;
+            }
+        }
+
+        for script in &self.psql_scripts {
+            server.run_psql_script(script)?;
+        }
+
+        if !self.migrations.is_empty() && !matches!(source, DataSource::Standby(_)) {
+            let descriptor = server.descriptor();
+            for migrate in &self.migrations {
+                (migrate.0)(&descriptor).map_err(PsqlServerError::MigrationFailed)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns a server this builder started to the state `start` left it
+    /// in, as far as databases go: everything but the defaults is dropped
+    /// and `test` is created and set up again.
+    pub(crate) fn recycle(&self, server: &PsqlServer) -> Result<(), PsqlServerError> {
+        server.reset_cluster()?;
+        let createdb = find_tool("createdb", server.bin_dir.as_deref())
+            .map_err(|_| PsqlServerError::CouldNotFindCreateDbCommand)?;
+        self.create_test_database(server, &createdb)?;
+        self.set_up(server, DataSource::InitDb)
+    }
+
+    /// Creates the data and socket directories in `base_path` and picks a
+    /// port.
+    fn prepare_dirs(&self, source: DataSource, base_path: &Path, initdb: &str,
+                    password: Option<&str>, version_num: u32, config_file: Option<String>)
+                    -> Result<PreparedDirs, PsqlServerError> {
+        let data_path = match (source, &self.data_dir) {
+            (DataSource::BaseBackup(_), _) | (DataSource::Standby(_), _) | (_, None) =>
+                base_path.join("data"),
+            (_, Some(data_dir)) => data_dir.clone()
+        }.to_str().unwrap().to_owned();
+        let tmp_path = base_path.join("tmp").to_str()
+            .unwrap().to_owned();
+        fs::create_dir(&tmp_path)
+            .map_err(PsqlServerError::IoError)?;
+
+        match source {
+            DataSource::InitDb => {
+                fs::create_dir_all(&data_path)
+                    .map_err(PsqlServerError::IoError)?;
+                self.run_initdb(initdb, &data_path, &base_path.join("pwfile"), password, version_num)?;
+            },
+            DataSource::Template(template) => {
+                let template_version = fs::read_to_string(template.join("PG_VERSION"))
+                    .map_err(|error| PsqlServerError::IoError(std::io::Error::new(
+                        error.kind(), format!("{} is not a cluster: {}", template.display(), error))))?;
+                if template_version.trim() != major_version(version_num) {
+                    return Err(PsqlServerError::VersionMismatch {
+                        data_dir: template_version.trim().to_owned(),
+                        server: major_version(version_num)
+                    });
+                }
+                // an empty `data_dir` is fine, but copy_dir creates it
+                if Path::new(&data_path).exists() {
+                    fs::remove_dir(&data_path)
+                        .map_err(PsqlServerError::IoError)?;
+                }
+                copy_dir(template, Path::new(&data_path))
+                    .map_err(PsqlServerError::IoError)?;
+            },
+            DataSource::BaseBackup(backup) =>
+                prepare_basebackup(backup, Path::new(&data_path), version_num)?,
+            DataSource::Standby(primary) =>
+                primary.clone_standby(&data_path, &default_cluster_name(base_path))?,
+            DataSource::Existing(data_dir) => {
+                let data_version = fs::read_to_string(data_dir.join("PG_VERSION"))
+                    .map_err(PsqlServerError::IoError)?;
+                if data_version.trim() != major_version(version_num) {
+                    return Err(PsqlServerError::VersionMismatch {
+                        data_dir: data_version.trim().to_owned(),
+                        server: major_version(version_num)
+                    });
+                }
+            }
+        }
+
+        // an existing cluster was set up when it was created, and a standby
+        // has the primary's certificates
+        if !matches!(source, DataSource::Existing(_) | DataSource::Standby(_)) {
+            if let Some(tls) = &self.tls {
+                tls.install(Path::new(&data_path))?;
+            }
+            #[cfg(feature = "tls")]
+            if self.self_signed_tls {
+                TlsConfig::install_self_signed(Path::new(&data_path))?;
+            }
+            if !self.auto_conf_params.is_empty() {
+                let path = Path::new(&data_path).join("postgresql.auto.conf");
+                let mut auto_conf = fs::read_to_string(&path)
+                    .unwrap_or_default();
+                for (name, value) in &self.auto_conf_params {
+                    auto_conf.push_str(&format!("{} = {}\n", name, quote_literal(value)));
+                }
+                fs::write(&path, auto_conf)
+                    .map_err(PsqlServerError::IoError)?;
+            }
+        }
+
+        let (socket_path, socket_dir) = if let Some(socket_dir) = &self.socket_dir {
+            (socket_dir.to_str().unwrap().to_owned(), None)
+        } else if socket_path_fits(Path::new(&tmp_path)) {
+            (tmp_path, None)
+        } else {
+            let socket_dir = TempDir::new_in("/tmp", registry::SOCKET_DIR_PREFIX.trim_end_matches('.'))
+                .and_then(|dir| registry::mark_owner(dir.path()).map(|()| dir))
+                .map_err(PsqlServerError::IoError)?;
+            if !socket_path_fits(socket_dir.path()) {
+                return Err(PsqlServerError::SocketPathTooLong(socket_dir.path().to_owned()));
+            }
+            (socket_dir.path().to_str().unwrap().to_owned(), Some(socket_dir))
+        };
+
+        // without TCP the port only names the socket, which can't clash in
+        // a socket directory of its own
+        let (port, reservation) = if !self.tcp && self.socket_dir.is_none() {
+            (5432, None)
+        } else {
+            // held through initdb, which takes long enough for another
+            // process to grab a port that was merely seen to be free
+            let (port, listener) = reserve_unused_port()
+                .map_err(PsqlServerError::IoError)?;
+            (port, Some(listener))
+        };
+
+        if let Some(mut config) = config_file {
+            // what the crate passes on the command line wins anyway, but
+            // the file should describe the server that actually runs
+            config.push_str(&format!(
+                "\n# added by testing-postgres\n\
+                 port = {}\n\
+                 listen_addresses = {}\n\
+                 unix_socket_directories = {}\n",
+                port, quote_literal(self.effective_listen_addresses()), quote_literal(&socket_path)));
+            fs::write(Path::new(&data_path).join("postgresql.conf"), config)
+                .map_err(PsqlServerError::IoError)?;
+        }
+
+        Ok(PreparedDirs { data_path, socket_path, socket_dir, port, reservation })
+    }
+
+    /// Fails if an option needs a newer server than `version_num`, which
+    /// postgres would otherwise reject with a less obvious error.
+    fn check_version(&self, version_num: u32) -> Result<(), PsqlServerError> {
+        let too_old = |what: String, required: u32| PsqlServerError::VersionTooOld {
+            what,
+            required: major_version(required),
+            server: major_version(version_num)
+        };
+        let required = self.auth_method.min_version_num();
+        if version_num < required {
+            return Err(too_old(format!("the `{}` auth method", self.auth_method.hba_name()), required));
+        }
+        if self.effective_locale_provider().is_some() && version_num < 150000 {
+            return Err(too_old("a database locale provider".to_owned(), 150000));
+        }
+        for (name, _) in self.effective_config_params() {
+            if let Some(required) = param_min_version_num(&name) {
+                if version_num < required {
+                    return Err(too_old(format!("`{}`", name), required));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs initdb into `data_path`, passing `password` through a temporary
+    /// `pwfile`.
+    fn run_initdb(&self, initdb: &str, data_path: &str, pwfile: &Path, password: Option<&str>,
+                  version_num: u32) -> Result<(), PsqlServerError> {
+        if let Some(password) = password {
+            fs::write(pwfile, password)
+                .map_err(PsqlServerError::IoError)?;
+        }
+        let mut initdb_command = Command::new(initdb);
+        initdb_command.env("LC_ALL", self.initdb_locale.as_deref().unwrap_or("C"));
+        set_umask(&mut initdb_command, self.umask);
+        let initdb_out = initdb_command
+            .args(self.initdb_args(data_path, password.map(|_| pwfile.to_str().unwrap()), version_num))
+            .output()
+            .unwrap_or_else(|_| panic!("failed to execute {}", initdb));
+        if password.is_some() {
+            fs::remove_file(pwfile)
+                .map_err(PsqlServerError::IoError)?;
+        }
+
+        if !initdb_out.status.success() {
+            return Err(PsqlServerError::InitDbFailed);
+        }
+        Ok(())
+    }
+
+    fn initdb_args(&self, data_path: &str, pwfile: Option<&str>, version_num: u32) -> Vec<String> {
+        let mut args: Vec<String> = ["-D", data_path, "--lc-messages=C", "-U", &self.superuser,
+                                     "-A", self.auth_method.hba_name()]
+            .iter().map(|arg| arg.to_string()).collect();
+        if let Some(pwfile) = pwfile {
+            args.push(format!("--pwfile={}", pwfile));
+        }
+        if self.allow_group_access {
+            args.push("--allow-group-access".to_owned());
+        }
+        if self.initdb_locale.is_none() {
+            // the C locale alone would make the cluster SQL_ASCII
+            args.push("--encoding=UTF8".to_owned());
+        }
+        if self.initdb_no_sync {
+            // spelled --nosync before 10
+            args.push(if version_num >= 100000 { "--no-sync" } else { "--nosync" }.to_owned());
+        } else if let Some(method) = &self.initdb_sync_method {
+            // added in 17, older versions always sync file by file
+            if version_num >= 170000 {
+                args.push(format!("--sync-method={}", method));
+            }
+        }
+        args
+    }
+
+    fn postgres_args(&self, port: &str, data_path: &str, socket_path: &str,
+                     default_cluster_name: &str) -> Vec<String> {
+        let mut args: Vec<String> = vec![
+            "-p".to_owned(), port.to_owned(),
+            "-D".to_owned(), data_path.to_owned(),
+            "-k".to_owned(), socket_path.to_owned(),
+            "-h".to_owned(), self.effective_listen_addresses().to_owned(),
+            "-c".to_owned(), "logging_collector=off".to_owned()];
+        let params = self.effective_config_params();
+        if !params.iter().any(|(name, _)| name == "cluster_name") {
+            args.push("-c".to_owned());
+            args.push(format!("cluster_name={}", default_cluster_name));
+        }
+        for (name, value) in &params {
+            args.push("-c".to_owned());
+            args.push(format!("{}={}", name, value));
+        }
+        args.extend(self.extra_postgres_args.iter().cloned());
+        args
+    }
+
+    fn createdb_args(&self, host: &str, port: &str, database: &str) -> Vec<String> {
+        let mut args: Vec<String> = ["-p", port, "-h", host, "-U", &self.superuser]
+            .iter().map(|arg| arg.to_string()).collect();
+        if let Some(owner) = &self.database_owner {
+            args.push("-O".to_owned());
+            args.push(owner.clone());
+        }
+        let provider = self.effective_locale_provider();
+        if self.use_template0 || provider.is_some() {
+            args.push("-T".to_owned());
+            args.push("template0".to_owned());
+        }
+        if let Some(provider) = provider {
+            args.push(format!("--locale-provider={}", provider.name()));
+        }
+        match (&self.icu_locale, provider) {
+            (Some(locale), _) => args.push(format!("--icu-locale={}", locale)),
+            // 15 wants one, and the cluster's C locale doesn't translate
+            (None, Some(LocaleProvider::Icu)) => args.push("--icu-locale=und".to_owned()),
+            _ => ()
+        }
+        args.push(database.to_owned());
+        args
+    }
+
+    fn effective_locale_provider(&self) -> Option<LocaleProvider> {
+        match (self.locale_provider, &self.icu_locale) {
+            (None, Some(_)) => Some(LocaleProvider::Icu),
+            (provider, _) => provider
+        }
+    }
+
+    fn effective_listen_addresses(&self) -> &str {
+        if self.tcp { &self.listen_addresses } else { "" }
+    }
+
+    fn effective_connection_options(&self) -> Vec<(String, String)> {
+        let mut options = self.connection_options.clone();
+        if (self.tls.is_some() || self.self_signed_tls)
+            && !options.iter().any(|(key, _)| key == "sslmode") {
+                options.insert(0, ("sslmode".to_owned(), "require".to_owned()));
+            }
+        options
+    }
+
+    /// The configuration parameters passed to postgres, with those implied by
+    /// other builder options merged in.
+    fn effective_config_params(&self) -> Vec<(String, String)> {
+        let mut params = self.config_params.clone();
+        for (name, value) in &self.fallible_config_params {
+            params.retain(|(n, _)| n != name);
+            params.push((name.clone(), value.clone()));
+        }
+        if !self.preload_extensions.is_empty() {
+            let mut libraries: Vec<String> = params.iter()
+                .filter(|(name, _)| name == "shared_preload_libraries")
+                .flat_map(|(_, value)| value.split(',').map(|l| l.trim().to_owned()))
+                .filter(|l| !l.is_empty())
+                .collect();
+            for extension in &self.preload_extensions {
+                if !libraries.contains(extension) {
+                    libraries.push(extension.clone());
+                }
+            }
+            params.retain(|(name, _)| name != "shared_preload_libraries");
+            params.push(("shared_preload_libraries".to_owned(), libraries.join(",")));
+        }
+        if self.tls.is_some() || self.self_signed_tls {
+            let mut tls_params = vec![
+                ("ssl", "on"),
+                ("ssl_cert_file", "server.crt"),
+                ("ssl_key_file", "server.key")];
+            if self.tls.as_ref().is_some_and(|tls| tls.ca_cert.is_some()) {
+                tls_params.push(("ssl_ca_file", "root.crt"));
+            }
+            for (name, value) in tls_params {
+                if !params.iter().any(|(n, _)| n == name) {
+                    params.push((name.to_owned(), value.to_owned()));
+                }
+            }
+        }
+        if !self.inherit_stdio && self.config_file.is_none()
+            && !params.iter().any(|(name, _)| name == "log_line_prefix") {
+                params.push(("log_line_prefix".to_owned(), DEFAULT_LOG_LINE_PREFIX.to_owned()));
+            }
+        params
+    }
+}
+
+/// Reads the version a postgres binary reports, e.g. `15.4`.
+fn binary_version(program: &str) -> Result<String, PsqlServerError> {
+    let out = Command::new(program)
+        .arg("--version")
+        .output()
+        .map_err(PsqlServerError::IoError)?;
+    // e.g. "postgres (PostgreSQL) 15.4 (Debian 15.4-1)" or "psql (PostgreSQL) 15.4"
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    stdout.split_whitespace().nth(2)
+        .map(str::to_owned)
+        .ok_or_else(|| PsqlServerError::UnknownVersion(stdout.trim().to_owned()))
+}
+
+/// Reads the version of a postgres binary in `server_version_num` form
+/// (e.g. `150004` for 15.4, `90624` for 9.6.24).
+fn binary_version_num(program: &str) -> Result<u32, PsqlServerError> {
+    let version = binary_version(program)?;
+    let parts: Vec<u32> = version.split('.')
+        .map(|part| part.chars().take_while(|c| c.is_ascii_digit()).collect::<String>())
+        .take_while(|part| !part.is_empty())
+        .map(|part| part.parse().unwrap())
+        .collect();
+    match parts.as_slice() {
+        [major, minor, patch, ..] if *major < 10 => Ok(major * 10000 + minor * 100 + patch),
+        [major, minor, ..] if *major >= 10 => Ok(major * 10000 + minor),
+        [major] if *major >= 10 => Ok(major * 10000),
+        _ => Err(PsqlServerError::UnknownVersion(version))
+    }
+}
+
+/// SQL features that only exist from a certain server version on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PgFeature {
+    /// `CREATE PROCEDURE` / `CALL` (11+)
+    Procedures,
+    /// `GENERATED ALWAYS AS (...) STORED` columns (12+)
+    GeneratedColumns,
+    /// Multirange types such as `int4multirange` (14+)
+    Multiranges,
+    /// The `MERGE` statement (15+)
+    MergeStatement,
+    /// `UNIQUE NULLS NOT DISTINCT` constraints (15+)
+    NullsNotDistinct,
+    /// `JSON_TABLE` and the SQL/JSON query functions (17+)
+    JsonTable
+}
+
+impl PgFeature {
+    /// The first `server_version_num` supporting the feature.
+    pub fn min_version_num(self) -> u32 {
+        match self {
+            PgFeature::Procedures => 110000,
+            PgFeature::GeneratedColumns => 120000,
+            PgFeature::Multiranges => 140000,
+            PgFeature::MergeStatement => 150000,
+            PgFeature::NullsNotDistinct => 150000,
+            PgFeature::JsonTable => 170000
+        }
+    }
+}
+
+/// Checks that the shared library and control file for `extension` are
+/// installed, using the `pg_config` belonging to `postgres` if there is one.
+/// Without `pg_config` the check is skipped and postgres reports the problem
+/// itself.
+fn check_extension_files(postgres: &str, extension: &str) -> Result<(), PsqlServerError> {
+    let pg_config = match find_pg_config(postgres) {
+        Some(pg_config) => pg_config,
+        None => return Ok(())
+    };
+    let out = Command::new(&pg_config)
+        .args(["--pkglibdir", "--sharedir"])
+        .output()
+        .map_err(PsqlServerError::IoError)?;
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let mut lines = stdout.lines();
+    let (pkglibdir, sharedir) = match (lines.next(), lines.next()) {
+        (Some(pkglibdir), Some(sharedir)) if out.status.success() => (pkglibdir, sharedir),
+        _ => return Ok(())
+    };
+
+    let library = Path::new(pkglibdir)
+        .join(format!("{}{}", extension, std::env::consts::DLL_SUFFIX));
+    let control = Path::new(sharedir).join("extension")
+        .join(format!("{}.control", extension));
+    let missing: Vec<PathBuf> = vec![library, control].into_iter()
+        .filter(|path| !path.exists())
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(PsqlServerError::ExtensionNotAvailable {
+            name: extension.to_owned(),
+            missing
+        })
+    }
+}
+
+/// Fails if `pg_config --configure` shows the server was built without
+/// `--with-<feature>` (or, for ICU from 16 on, where it is the default,
+/// with `--without-icu`). Like `check_extension_files`, this is skipped
+/// without `pg_config`, leaving postgres to reject the setting itself.
+fn check_built_with(postgres: &str, feature: &str, version_num: u32) -> Result<(), PsqlServerError> {
+    let pg_config = match find_pg_config(postgres) {
+        Some(pg_config) => pg_config,
+        None => return Ok(())
+    };
+    let out = Command::new(&pg_config)
+        .arg("--configure")
+        .output()
+        .map_err(PsqlServerError::IoError)?;
+    if !out.status.success() {
+        return Ok(());
+    }
+    let configure = String::from_utf8_lossy(&out.stdout);
+    let has_option = |option: String| configure.split_whitespace().any(|arg| arg == option);
+    let built_with = if feature == "icu" && version_num >= 160000 {
+        !has_option(format!("'--without-{}'", feature))
+    } else {
+        has_option(format!("'--with-{}'", feature))
+    };
+    if built_with {
+        Ok(())
+    } else {
+        Err(PsqlServerError::NotBuiltWith(feature.to_owned()))
+    }
+}
+
+/// The `pg_config` next to `postgres`, or else the one on the `PATH`.
+fn find_pg_config(postgres: &str) -> Option<String> {
+    fs::canonicalize(postgres).ok()
+        .map(|path| path.with_file_name("pg_config"))
+        .filter(|path| path.exists())
+        .map(|path| path.to_string_lossy().into_owned())
+        .or_else(|| which("pg_config").ok())
+}
+
+/// Parses an LSN in postgres' `XXXXXXXX/XXXXXXXX` text form.
+fn parse_lsn(lsn: &str) -> Result<u64, PsqlServerError> {
+    let invalid = || PsqlServerError::InvalidLsn(lsn.to_owned());
+    let mut parts = lsn.splitn(2, '/');
+    let high = parts.next().ok_or_else(invalid)?;
+    let low = parts.next().ok_or_else(invalid)?;
+    let high = u32::from_str_radix(high, 16).map_err(|_| invalid())?;
+    let low = u32::from_str_radix(low, 16).map_err(|_| invalid())?;
+    Ok(u64::from(high) << 32 | u64::from(low))
+}
+
+/// Quotes `value` for use as an SQL string literal.
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Quotes `ident` for use as an SQL identifier.
+fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+/// Certificates for serving TLS connections, see `PsqlServerBuilder::tls`.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub server_cert: PathBuf,
+    pub server_key: PathBuf,
+    /// When set, TCP connections over TLS must present a client certificate
+    /// signed by this CA (`hostssl ... cert` in `pg_hba.conf`). Connections
+    /// without TLS are still trusted.
+    pub ca_cert: Option<PathBuf>
+}
+
+impl TlsConfig {
+    /// Copies the certificates into `data_dir` and, if a CA is given, requires
+    /// client certificates for TLS connections.
+    fn install(&self, data_dir: &Path) -> Result<(), PsqlServerError> {
+        let mut files = vec![(&self.server_cert, "server.crt"), (&self.server_key, "server.key")];
+        if let Some(ca_cert) = &self.ca_cert {
+            files.push((ca_cert, "root.crt"));
+        }
+        for (from, to) in files {
+            fs::copy(from, data_dir.join(to))
+                .map_err(|e| PsqlServerError::TlsConfig(
+                    format!("could not copy {}: {}", from.display(), e)))?;
+        }
+        restrict_to_owner(&data_dir.join("server.key"))?;
+
+        if self.ca_cert.is_some() {
+            let hba_path = data_dir.join("pg_hba.conf");
+            let hba = fs::read_to_string(&hba_path)
+                .map_err(PsqlServerError::IoError)?;
+            fs::write(&hba_path, format!(
+                "hostssl all all 127.0.0.1/32 cert\n\
+                 hostssl all all ::1/128 cert\n{}", hba))
+                .map_err(PsqlServerError::IoError)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tls")]
+impl TlsConfig {
+    /// Writes a new self-signed certificate and its key into `data_dir`.
+    fn install_self_signed(data_dir: &Path) -> Result<(), PsqlServerError> {
+        let generated = rcgen::generate_simple_self_signed(
+            vec!["localhost".to_owned(), "127.0.0.1".to_owned()])
+            .map_err(|e| PsqlServerError::TlsConfig(
+                format!("could not generate a certificate: {}", e)))?;
+        fs::write(data_dir.join("server.crt"), generated.cert.pem())
+            .and_then(|_| fs::write(data_dir.join("server.key"), generated.key_pair.serialize_pem()))
+            .map_err(PsqlServerError::IoError)?;
+        restrict_to_owner(&data_dir.join("server.key"))
+    }
+}
+
+/// Makes a file readable by its owner only; postgres refuses to use a private
+/// key that others can read.
+fn restrict_to_owner(path: &Path) -> Result<(), PsqlServerError> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+            .map_err(PsqlServerError::IoError)?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Where a new server's data directory comes from.
+#[derive(Clone, Copy)]
+enum DataSource<'a> {
+    InitDb,
+    /// A copy of `PsqlServerBuilder::template_data_dir`.
+    Template(&'a Path),
+    BaseBackup(&'a Path),
+    /// A cluster left in `PsqlServerBuilder::data_dir` by an earlier run.
+    Existing(&'a Path),
+    /// A standby streaming from a running server.
+    Standby(&'a PsqlServer)
+}
+
+/// Copies a base backup into `data_dir` and adjusts it so it can run as a
+/// standalone throwaway server.
+fn prepare_basebackup(backup: &Path, data_dir: &Path, version_num: u32) -> Result<(), PsqlServerError> {
+    if backup.is_dir() {
+        copy_dir(backup, data_dir)
+            .map_err(PsqlServerError::IoError)?;
+    } else {
+        fs::create_dir(data_dir)
+            .map_err(PsqlServerError::IoError)?;
+        let tar_out = Command::new("tar")
+            .arg("-xf").arg(backup)
+            .arg("-C").arg(data_dir)
+            .output()
+            .map_err(PsqlServerError::IoError)?;
+        if !tar_out.status.success() {
+            return Err(PsqlServerError::IoError(std::io::Error::other(
+                format!("could not extract {}: {}", backup.display(),
+                        String::from_utf8_lossy(&tar_out.stderr).trim()))));
+        }
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(data_dir, fs::Permissions::from_mode(0o700))
+            .map_err(PsqlServerError::IoError)?;
+    }
+
+    let data_version = fs::read_to_string(data_dir.join("PG_VERSION"))
+        .map_err(PsqlServerError::IoError)?;
+    let data_version = data_version.trim();
+    if data_version != major_version(version_num) {
+        return Err(PsqlServerError::VersionMismatch {
+            data_dir: data_version.to_owned(),
+            server: major_version(version_num)
+        });
+    }
+
+    for file in &["postmaster.pid", "standby.signal", "recovery.signal"] {
+        let path = data_dir.join(file);
+        if path.exists() {
+            fs::remove_file(path)
+                .map_err(PsqlServerError::IoError)?;
+        }
+    }
+    fs::write(data_dir.join("pg_hba.conf"),
+              "local all all trust\n\
+               host all all 127.0.0.1/32 trust\n\
+               host all all ::1/128 trust\n")
+        .map_err(PsqlServerError::IoError)?;
+    for file in &["postgresql.conf", "pg_ident.conf"] {
+        let path = data_dir.join(file);
+        if !path.exists() {
+            fs::write(path, "")
+                .map_err(PsqlServerError::IoError)?;
+        }
+    }
+
+    let mut auto_conf = fs::read_to_string(data_dir.join("postgresql.auto.conf"))
+        .unwrap_or_default();
+    auto_conf.push_str(&format!(
+        "\n# added by testing-postgres\n\
+         listen_addresses = '127.0.0.1'\n\
+         hba_file = {}\n\
+         ident_file = {}\n\
+         archive_mode = off\n\
+         primary_conninfo = ''\n\
+         primary_slot_name = ''\n",
+        quote_literal(&data_dir.join("pg_hba.conf").to_string_lossy()),
+        quote_literal(&data_dir.join("pg_ident.conf").to_string_lossy())));
+    fs::write(data_dir.join("postgresql.auto.conf"), auto_conf)
+        .map_err(PsqlServerError::IoError)
+}
+
+/// The major version as written to `PG_VERSION`, e.g. "15" or "9.6".
+fn major_version(version_num: u32) -> String {
+    if version_num >= 100000 {
+        format!("{}", version_num / 10000)
+    } else {
+        format!("{}.{}", version_num / 10000, version_num / 100 % 100)
+    }
+}
+
+/// What `PsqlServerBuilder::prepare_dirs` set up.
+struct PreparedDirs {
+    data_path: String,
+    socket_path: String,
+    socket_dir: Option<TempDir>,
+    port: u16,
+    /// Holds `port` until postgres is spawned.
+    reservation: Option<TcpListener>
+}
+
+/// Everything needed to (re)spawn the postgres process.
+struct PostgresCommand {
+    program: String,
+    args: Vec<String>,
+    /// Set when postgres is managed through `pg_ctl`.
+    pg_ctl: Option<String>,
+    data_dir: String,
+    startup_timeout: Duration,
+    inherit_stdio: bool,
+    nice: Option<i32>,
+    oom_score_adj: Option<i32>,
+    core_dumps: bool,
+    umask: Option<u32>
+}
+
+impl PostgresCommand {
+    /// Spawns postgres, capturing its stderr into `log` unless stdio is
+    /// inherited. With `pg_ctl` the returned child is `pg_ctl start`, which
+    /// exits once the server is ready; postgres inherits its stdout.
+    fn spawn(&self, log: &Arc<ServerLog>) -> Result<Child, std::io::Error> {
+        let (stdout, stderr) = if self.inherit_stdio {
+            (Stdio::inherit(), Stdio::inherit())
+        } else {
+            (Stdio::piped(), Stdio::piped())
+        };
+        let mut command = match &self.pg_ctl {
+            Some(pg_ctl) => {
+                let options: Vec<String> = self.args.iter().map(|arg| shell_quote(arg)).collect();
+                let mut command = Command::new(pg_ctl);
+                command.args(["start", "-w",
+                              "-t", &format!("{}", self.startup_timeout.as_secs().max(1)),
+                              "-D", &self.data_dir,
+                              "-o", &options.join(" ")]);
+                command
+            },
+            None => {
+                let mut command = Command::new(&self.program);
+                command.args(&self.args);
+                command
+            }
+        };
+        command.stdout(stdout)
+            .stderr(stderr);
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            if let Some(nice) = self.nice {
+                // runs in the forked child, so only async-signal-safe calls
+                unsafe {
+                    command.pre_exec(move || {
+                        if libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) != 0 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                        Ok(())
+                    });
+                }
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(adj) = self.oom_score_adj {
+                // formatted up front, as allocating isn't safe after fork
+                let value = adj.to_string().into_bytes();
+                unsafe {
+                    command.pre_exec(move || {
+                        let path = b"/proc/self/oom_score_adj\0";
+                        let fd = libc::open(path.as_ptr() as *const libc::c_char, libc::O_WRONLY);
+                        if fd == -1 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                        let written = libc::write(fd, value.as_ptr() as *const libc::c_void, value.len());
+                        let error = std::io::Error::last_os_error();
+                        libc::close(fd);
+                        if written == -1 {
+                            return Err(error);
+                        }
+                        Ok(())
+                    });
+                }
+            }
+            if self.core_dumps {
+                unsafe {
+                    command.pre_exec(|| {
+                        let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+                        if libc::getrlimit(libc::RLIMIT_CORE, &mut limit) != 0 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                        // raising the hard limit needs privileges
+                        limit.rlim_cur = limit.rlim_max;
+                        if libc::setrlimit(libc::RLIMIT_CORE, &limit) != 0 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                        Ok(())
+                    });
+                }
+            }
+            if self.pg_ctl.is_none() {
+                // its own session, so a Ctrl-C meant for the tests doesn't
+                // reach it and `kill_postgres` can take what is left of the
+                // process group
+                unsafe {
+                    command.pre_exec(|| {
+                        if libc::setsid() == -1 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                        Ok(())
+                    });
+                }
+            }
+        }
+        set_umask(&mut command, self.umask);
+        let mut child = command.spawn()?;
+        if self.pg_ctl.is_some() {
+            // pg_ctl sends the server's stderr to its stdout, and keeps its
+            // own stderr for its messages
+            if let Some(stdout) = child.stdout.take() {
+                log.capture(stdout);
+            }
+            return Ok(child);
+        }
+        // both pipes are read continuously, or a chatty server (e.g. with
+        // log_statement=all) would block once a pipe buffer fills up
+        if let Some(mut stdout) = child.stdout.take() {
+            thread::spawn(move || std::io::copy(&mut stdout, &mut std::io::sink()));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            log.capture(stderr);
+        }
+        Ok(child)
+    }
+
+    /// Replaces any `-c name=...` argument with `-c name=value`.
+    fn set_param(&mut self, name: &str, value: &str) {
+        self.remove_param(name);
+        self.args.push("-c".to_owned());
+        self.args.push(format!("{}={}", name, value));
+    }
+
+    /// Drops any `-c name=...` argument.
+    fn remove_param(&mut self, name: &str) {
+        let prefix = format!("{}=", name);
+        let mut i = 0;
+        while i + 1 < self.args.len() {
+            if self.args[i] == "-c" && self.args[i + 1].starts_with(&prefix) {
+                self.args.drain(i..i + 2);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Whether `pg_ctl status` reports the server as running.
+    fn pg_ctl_running(&self, pg_ctl: &str) -> bool {
+        Command::new(pg_ctl)
+            .args(["status", "-D", &self.data_dir])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    }
+
+    /// Stops the server with `pg_ctl stop` in the given shutdown `mode`.
+    fn pg_ctl_stop(&self, pg_ctl: &str, mode: &str) -> Result<(), std::io::Error> {
+        let out = Command::new(pg_ctl)
+            .args(["stop", "-w", "-m", mode, "-D", &self.data_dir])
+            .output()?;
+        if !out.status.success() {
+            return Err(std::io::Error::other(format!(
+                "pg_ctl stop failed: {}", String::from_utf8_lossy(&out.stderr).trim())));
+        }
+        Ok(())
+    }
+}
+
+/// How the postgres process is managed, see `PsqlServerBuilder::lifecycle`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lifecycle {
+    /// Runs postgres as a child process, polling `pg_isready` until it is
+    /// up and killing it when the server is dropped.
+    Direct,
+    /// Uses `pg_ctl start -w`, `pg_ctl stop -m fast` (`-m immediate` when
+    /// dropped) and `pg_ctl status`.
+    PgCtl
+}
+
+/// Makes `command` run with `umask`, if set.
+fn set_umask(command: &mut Command, umask: Option<u32>) {
+    #[cfg(unix)]
+    if let Some(umask) = umask {
+        use std::os::unix::process::CommandExt;
+        // runs in the forked child, and umask is async-signal-safe
+        unsafe {
+            command.pre_exec(move || {
+                libc::umask(umask as libc::mode_t);
+                Ok(())
+            });
+        }
+    }
+    #[cfg(not(unix))]
+    if umask.is_some() {
+        let _ = command;
+        eprintln!("testing-postgres: umask is not supported on this platform, ignoring it");
+    }
+}
+
+/// `host` as written in a URL, with IPv6 addresses in brackets and socket
+/// directories percent-encoded.
+fn url_host(host: &str) -> String {
+    if host.starts_with('/') {
+        percent_encode(host)
+    } else if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]", host)
+    } else {
+        host.to_owned()
+    }
+}
+
+/// Quotes an argument for the command line `pg_ctl` hands to the shell.
+fn shell_quote(arg: &str) -> String {
+    if cfg!(windows) {
+        format!("\"{}\"", arg.replace('"', "\\\""))
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Finds a raw postgres argument that would override the port, data
+/// directory, sockets or listen address set by the crate.
+fn find_managed_postgres_arg(args: &[String]) -> Option<&String> {
+    const PARAMS: &[&str] = &["port", "data_directory", "unix_socket_directories", "listen_addresses"];
+    let mut after_c = false;
+    for arg in args {
+        // `-c name=value` may be given as one argument or two
+        let param = if after_c { Some(arg.as_str()) } else { arg.strip_prefix("-c") }
+            .or_else(|| arg.strip_prefix("--"))
+            .map(|param| param.trim_start().replace('-', "_"));
+        let managed = match param {
+            Some(param) => PARAMS.iter().any(|name| param.starts_with(name)),
+            None => ["-p", "-D", "-k", "-h", "-i"].iter().any(|flag| arg.starts_with(flag))
+        };
+        if managed {
+            return Some(arg);
+        }
+        after_c = arg == "-c";
+    }
+    None
+}
+
+/// `testing-postgres-<suffix>`, from a base directory like `testing-postgres.<suffix>`.
+fn default_cluster_name(base_dir: &Path) -> String {
+    let name = base_dir.file_name().unwrap_or_default().to_string_lossy();
+    let suffix = name.strip_prefix(registry::BASE_DIR_PREFIX).unwrap_or(&name);
+    format!("testing-postgres-{}", suffix)
+}
+
+/// The variables of `PsqlServer::export_repro_script`, which `dry_run`
+/// also uses as placeholders.
+const SCRIPT_VARIABLES: &[&str] = &["DATA_DIR", "SOCKET_DIR", "PORT", "CLUSTER_NAME", "PWFILE"];
+
+/// Quotes `arg` for a POSIX shell, leaving any `$VARIABLE` of
+/// `SCRIPT_VARIABLES` in it to be expanded.
+fn script_word(arg: &str) -> String {
+    let mut word = String::new();
+    let mut rest = arg;
+    while !rest.is_empty() {
+        let variable = SCRIPT_VARIABLES.iter()
+            .filter_map(|name| rest.find(&format!("${}", name)).map(|at| (at, *name)))
+            .min();
+        let literal = &rest[..variable.map_or(rest.len(), |(at, _)| at)];
+        if !literal.is_empty() {
+            word.push_str(&format!("'{}'", literal.replace('\'', "'\\''")));
+        }
+        rest = &rest[literal.len()..];
+        if let Some((_, name)) = variable {
+            word.push_str(&format!("\"${}\"", name));
+            rest = &rest[name.len() + 1..];
+        }
+    }
+    if word.is_empty() { "''".to_owned() } else { word }
+}
+
+fn script_command(command: &[String]) -> String {
+    command.iter().map(|arg| script_word(arg)).collect::<Vec<_>>().join(" ")
+}
+
+fn command_line(program: &str, args: Vec<String>) -> Vec<String> {
+    let mut command = vec![program.to_owned()];
+    command.extend(args);
+    command
+}
+
+/// A command for one of the client tools (psql, createdb, ...) the crate runs
+/// against its own server.
+fn client_command<S: AsRef<std::ffi::OsStr>>(program: S) -> Command {
+    let mut command = Command::new(program);
+    // the crate's own connections never need TLS, and must not be caught by
+    // the `hostssl ... cert` rule when client certificates are required
+    command.env("PGSSLMODE", "disable");
+    command
+}
+
+/// What `pg_isready` reported about a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Readiness {
+    Accepting,
+    /// Up, but still starting (or shutting down).
+    Rejecting,
+    /// Nothing is listening, or not yet.
+    NoResponse
+}
+
+fn pg_isready(program: &str, port: u16, host: &str, user: &str) -> Result<Readiness, PsqlServerError> {
+    let out = client_command(program)
+        .args(["-p", &format!("{}", port),
+               "-h", host,
+               "-U", user])
+        .output()
+        .map_err(PsqlServerError::IoError)?;
+    match out.status.code() {
+        Some(0) => Ok(Readiness::Accepting),
+        Some(1) => Ok(Readiness::Rejecting),
+        Some(2) => Ok(Readiness::NoResponse),
+        // 3: the arguments were invalid
+        _ => Err(PsqlServerError::PgIsReadyFailed(
+            String::from_utf8_lossy(&out.stderr).trim().to_owned()))
+    }
+}
+
+/// Asks postgres to shut down (a "fast" shutdown on unix) and waits for it to exit.
+fn shutdown(process: &mut Child) -> Result<(), std::io::Error> {
+    // once reaped, its pid may already belong to another process
+    if process.try_wait()?.is_some() {
+        return Ok(());
+    }
+    #[cfg(unix)]
+    unsafe {
+        libc::kill(process.id() as libc::pid_t, libc::SIGINT);
+    }
+    #[cfg(not(unix))]
+    process.kill()?;
+    process.wait()?;
+    Ok(())
+}
+
+/// Stops postgres along with its backends. On unix the postmaster gets an
+/// "immediate" shutdown (SIGQUIT), which has it kill its children and wait
+/// for them. Each backend leads a session of its own, out of reach of a
+/// signal to the postmaster's process group, so if the postmaster doesn't
+/// exit in time they are killed one by one.
+fn kill_postgres(process: &mut Child) -> Result<(), std::io::Error> {
+    #[cfg(unix)]
+    {
+        if process.try_wait()?.is_some() {
+            return Ok(());
+        }
+        // listed first, as they are reparented once the postmaster is gone
+        let children = child_pids(process.id()).unwrap_or_default();
+        unsafe {
+            libc::kill(process.id() as libc::pid_t, libc::SIGQUIT);
+        }
+        let deadline = Instant::now() + KILL_TIMEOUT;
+        while process.try_wait()?.is_none() {
+            if Instant::now() >= deadline {
+                unsafe {
+                    libc::kill(-(process.id() as libc::pid_t), libc::SIGKILL);
+                    for pid in children {
+                        libc::kill(pid as libc::pid_t, libc::SIGKILL);
+                    }
+                }
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    #[cfg(not(unix))]
+    process.kill()?;
+    process.wait()?;
+    Ok(())
+}
+
+/// The processes whose parent is `parent`, according to `ps`.
+#[cfg(unix)]
+fn child_pids(parent: u32) -> Result<Vec<u32>, std::io::Error> {
+    let out = Command::new("ps")
+        .args(["-A", "-o", "pid=", "-o", "ppid="])
+        .output()?;
+    Ok(String::from_utf8_lossy(&out.stdout).lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().map(|field| field.parse::<u32>().ok());
+            match (fields.next()??, fields.next()??) {
+                (pid, ppid) if ppid == parent => Some(pid),
+                _ => None
+            }
+        })
+        .collect())
+}
+
+fn remove_dir_if_exists(path: &Path) -> Result<(), std::io::Error> {
+    match fs::remove_dir_all(path) {
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<(), std::io::Error> {
+    fs::create_dir(to)?;
+    fs::set_permissions(to, fs::metadata(from)?.permissions())?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Connection details for a running server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+    /// The directory holding the server's unix socket, if it has one.
+    pub socket_dir: Option<PathBuf>
+}
+
+/// How to connect to a server's default database as the superuser, see
+/// `PsqlServer::descriptor`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerDescriptor {
+    pub endpoint: Endpoint,
+    pub user: String,
+    pub password: Option<String>,
+    pub database: String,
+    /// The `PsqlServer::connection_string`.
+    pub url: String
+}
+
+/// Everything a server was started with, see `PsqlServer::resolved_config`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedConfig {
+    pub postgres: PathBuf,
+    pub pg_ctl: Option<PathBuf>,
+    pub pg_isready: PathBuf,
+    /// Where the binaries of `PsqlServerBuilder::major_version` were found.
+    pub bin_dir: Option<PathBuf>,
+    pub version_num: u32,
+    pub lifecycle: Lifecycle,
+    pub data_dir: PathBuf,
+    pub socket_dir: PathBuf,
+    pub port: u16,
+    pub listen_addresses: String,
+    pub connect_host: String,
+    pub superuser: String,
+    pub auth_method: AuthMethod,
+    pub database: String,
+    /// Every `-c name=value` passed to postgres, in order.
+    pub settings: Vec<(String, String)>,
+    /// The full postgres command line, without the program.
+    pub args: Vec<String>
+}
+
+type MigrateFn = dyn Fn(&ServerDescriptor) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    + Send + Sync;
+
+/// A hook added with `PsqlServerBuilder::migrate_with`.
+#[derive(Clone)]
+struct MigrationHook(Arc<MigrateFn>);
+
+impl fmt::Debug for MigrationHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MigrationHook")
+    }
+}
+
+/// A callback added with `PsqlServerBuilder::on_exit`.
+#[derive(Clone)]
+struct ExitHook(Arc<dyn Fn(ExitStatus) + Send + Sync>);
+
+impl fmt::Debug for ExitHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ExitHook")
+    }
+}
+
+/// Polls the server's child process until the server is dropped, calling
+/// `on_exit` once for each postgres process that exits while still in
+/// `process`. `PsqlServer::stop` takes the process out before stopping it.
+fn watch_for_exit(process: Weak<Mutex<Option<Child>>>, on_exit: ExitHook) {
+    thread::spawn(move || {
+        let mut reported = None;
+        while let Some(process) = process.upgrade() {
+            let exited = process.lock().unwrap_or_else(|e| e.into_inner()).as_mut()
+                .and_then(|child| Some((child.id(), child.try_wait().ok()??)));
+            drop(process);
+            if let Some((pid, status)) = exited {
+                if reported != Some(pid) {
+                    reported = Some(pid);
+                    (on_exit.0)(status);
+                }
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    });
+}
+
+static NEXT_CONNECTION_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A connection to the server held open by an idle `psql` process, see
+/// `PsqlServer::open_connections`.
+pub struct Connection {
+    process: Child,
+    application_name: String
+}
+
+impl Connection {
+    /// The `application_name` identifying this connection in `pg_stat_activity`.
+    pub fn application_name(&self) -> &str {
+        &self.application_name
+    }
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Connection {{ application_name: {} }}", self.application_name)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+/// Client connections by state, see `PsqlServer::activity`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ActivitySnapshot {
+    pub total_backends: usize,
+    pub active: usize,
+    pub idle: usize,
+    /// Including transactions that failed and haven't been rolled back yet.
+    pub idle_in_transaction: usize
+}
+
+/// A row of `pg_stat_statements`, see `PsqlServer::top_statements`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatementStat {
+    /// The normalized statement text, with constants replaced by `$n`.
+    pub query: String,
+    pub calls: u64,
+    pub total_time: Duration,
+    pub rows: u64
+}
+
+/// When a server's temporary directory (data directory included) is deleted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CleanupPolicy {
+    /// Always, when the server is dropped or fails to start.
+    Always,
+    /// Never; the directory is left behind for inspection.
+    Never,
+    /// Only after a successful start, and not when dropped during a panic,
+    /// so the directory of a failing test is kept.
+    OnSuccess
+}
+
+/// A transaction isolation level, see
+/// `PsqlServerBuilder::default_transaction_isolation`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsolationLevel {
+    /// Behaves like `ReadCommitted` in PostgreSQL.
+    ReadUncommitted,
+    ReadCommitted,
+    RepeatableRead,
+    Serializable
+}
+
+impl IsolationLevel {
+    /// The name used in SQL and by `transaction_isolation`, e.g. `repeatable read`.
+    pub fn name(self) -> &'static str {
+        match self {
+            IsolationLevel::ReadUncommitted => "read uncommitted",
+            IsolationLevel::ReadCommitted => "read committed",
+            IsolationLevel::RepeatableRead => "repeatable read",
+            IsolationLevel::Serializable => "serializable"
+        }
+    }
+}
+
+/// The signals `PsqlServer::pause` and `resume` send.
+#[derive(Clone, Copy)]
+enum Signal {
+    Stop,
+    Continue
+}
+
+/// Whether postgres uses huge pages for shared memory, see
+/// `PsqlServerBuilder::huge_pages`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HugePages {
+    On,
+    Try,
+    Off
+}
+
+impl HugePages {
+    fn name(self) -> &'static str {
+        match self {
+            HugePages::On => "on",
+            HugePages::Try => "try",
+            HugePages::Off => "off"
+        }
+    }
+}
+
+/// Whether a string literal may escape a quote as `\'`, see
+/// `PsqlServerBuilder::backslash_quote`. It only matters for literals
+/// where backslashes are escapes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackslashQuote {
+    On,
+    Off,
+    /// Allowed unless the client encoding could hide a quote in a
+    /// multibyte character, the default.
+    SafeEncoding
+}
+
+impl BackslashQuote {
+    fn name(self) -> &'static str {
+        match self {
+            BackslashQuote::On => "on",
+            BackslashQuote::Off => "off",
+            BackslashQuote::SafeEncoding => "safe_encoding"
+        }
+    }
+}
+
+/// How TOAST compresses large values, see
+/// `PsqlServerBuilder::default_toast_compression`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToastCompression {
+    Pglz,
+    /// Needs postgres built with `--with-lz4`.
+    Lz4
+}
+
+impl ToastCompression {
+    fn name(self) -> &'static str {
+        match self {
+            ToastCompression::Pglz => "pglz",
+            ToastCompression::Lz4 => "lz4"
+        }
+    }
+}
+
+/// Where a database's collations come from, see
+/// `PsqlServerBuilder::locale_provider`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocaleProvider {
+    /// The operating system's C library.
+    Libc,
+    /// ICU. Needs postgres built with `--with-icu`.
+    Icu
+}
+
+impl LocaleProvider {
+    fn name(self) -> &'static str {
+        match self {
+            LocaleProvider::Libc => "libc",
+            LocaleProvider::Icu => "icu"
+        }
+    }
+}
+
+/// How clients authenticate, used for both local and TCP connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMethod {
+    /// No password is checked.
+    Trust,
+    /// A cleartext password.
+    Password,
+    /// An MD5 challenge (SCRAM if the password is stored as SCRAM).
+    Md5,
+    /// A SCRAM-SHA-256 exchange, needs PostgreSQL 10 or newer.
+    ScramSha256
+}
+
+impl AuthMethod {
+    fn hba_name(self) -> &'static str {
+        match self {
+            AuthMethod::Trust => "trust",
+            AuthMethod::Password => "password",
+            AuthMethod::Md5 => "md5",
+            AuthMethod::ScramSha256 => "scram-sha-256"
+        }
+    }
+
+    fn needs_password(self) -> bool {
+        self != AuthMethod::Trust
+    }
+
+    fn min_version_num(self) -> u32 {
+        match self {
+            AuthMethod::ScramSha256 => 100000,
+            _ => 0
+        }
+    }
+}
+
+/// The first `server_version_num` knowing the configuration parameter
+/// `name`, for parameters added after 9.6 that tests commonly set.
+fn param_min_version_num(name: &str) -> Option<u32> {
+    match name {
+        "jit" => Some(110000),
+        "ssl_min_protocol_version" | "ssl_max_protocol_version" => Some(120000),
+        "default_toast_compression" | "idle_session_timeout" => Some(140000),
+        _ => None
+    }
+}
+
+/// The lines between the common start and end of `before` and `after`,
+/// prefixed with `-` and `+` respectively.
+fn line_diff(before: &str, after: &str) -> String {
+    let before: Vec<&str> = before.lines().collect();
+    let after: Vec<&str> = after.lines().collect();
+    let prefix = before.iter().zip(&after)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = before[prefix..].iter().rev().zip(after[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let removed = before[prefix..before.len() - suffix].iter().map(|line| format!("-{}", line));
+    let added = after[prefix..after.len() - suffix].iter().map(|line| format!("+{}", line));
+    removed.chain(added).collect::<Vec<_>>().join("\n")
+}
+
+/// A random alphanumeric password.
+fn random_password() -> String {
+    const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+    let mut bytes = [0u8; 24];
+    let from_os = cfg!(unix) && fs::File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+        .is_ok();
+    if !from_os {
+        // std seeds each RandomState's keys from the OS
+        use std::hash::{BuildHasher, Hasher};
+        for chunk in bytes.chunks_mut(8) {
+            let value = std::collections::hash_map::RandomState::new().build_hasher().finish();
+            chunk.copy_from_slice(&value.to_le_bytes()[..chunk.len()]);
+        }
+    }
+    bytes.iter().map(|byte| CHARS[*byte as usize % CHARS.len()] as char).collect()
+}
+
+/// Percent-encodes everything but unreserved characters, for URL components.
+fn percent_encode(value: &str) -> String {
+    value.bytes().map(|byte| match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' =>
+            (byte as char).to_string(),
+        _ => format!("%{:02X}", byte)
+    }).collect()
+}
+
+/// Quotes `value` for a `.env` file if it contains anything but plain
+/// URL and path characters.
+fn dotenv_value(value: &str) -> String {
+    if value.chars().all(|c| c.is_ascii_alphanumeric() || "-._~/:@?&=%+,".contains(c)) {
+        value.to_owned()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// A directory holding the base directories of several servers. It is
+/// removed once the last server is dropped, unless one of them kept its own
+/// directory.
+struct SharedDir(PathBuf);
+
+impl Drop for SharedDir {
+    fn drop(&mut self) {
+        // only succeeds when empty, which it isn't if a server kept its
+        // directory
+        registry::unmark_owner(&self.0);
+        let _ = fs::remove_dir(&self.0);
+    }
+}
+
+/// Leaves `dir` on disk instead of deleting it, telling the user where it is.
+fn keep_dir(dir: Box<dyn DataStore>) {
+    // a kept directory is never an orphan
+    registry::unmark_owner(dir.path());
+    eprintln!("testing-postgres: keeping {}", dir.keep().display());
+}
+
+/// How `PsqlServer::run_migrations_dir_with` groups files into transactions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationTransaction {
+    /// All files in one transaction, so a failure leaves no file applied.
+    Single,
+    /// Each file in its own transaction, so files before a failure stay applied.
+    PerFile
+}
+
+/// Drops a database when `run` is called, see `PsqlServer::drop_database`.
+#[must_use]
+pub struct DropDatabase<'a> {
+    server: &'a PsqlServer,
+    db: &'a str,
+    force: bool
+}
+
+impl DropDatabase<'_> {
+    /// Terminates connections to the database first, avoiding "database is
+    /// being accessed by other users" errors.
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    pub fn run(self) -> Result<(), PsqlServerError> {
+        if self.force {
+            self.server.terminate_connections(self.db)?;
+        }
+        self.server.connect("postgres")?
+            .execute(&format!("DROP DATABASE {}", quote_ident(self.db)))
+    }
+}
+
+/// A schema that is dropped (with everything in it) when this goes out of
+/// scope, see `PsqlServer::scoped_schema`.
+pub struct SchemaGuard<'a> {
+    server: &'a PsqlServer,
+    db: String,
+    schema: String
+}
+
+impl SchemaGuard<'_> {
+    pub fn schema(&self) -> &str {
+        &self.schema
+    }
+
+    /// Opens a connection to the schema's database with `search_path` set
+    /// to just the schema, so unqualified names resolve inside it.
+    pub fn connect(&self) -> Result<PsqlConnection, PsqlServerError> {
+        let mut connection = self.server.connect(&self.db)?;
+        connection.set_guc("search_path", &quote_ident(&self.schema))?;
+        Ok(connection)
+    }
+}
+
+impl Drop for SchemaGuard<'_> {
+    fn drop(&mut self) {
+        // the server may already be gone, and there is nothing left to
+        // clean up then
+        let _ = self.server.drop_schema(&self.db, &self.schema, true);
+    }
+}
+
+/// A copy of a server's data directory, taken with `PsqlServer::snapshot`.
+#[derive(Debug)]
+pub struct Snapshot {
+    path: PathBuf
+}
+
+impl Snapshot {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl PsqlServer {
+    /// Copies the whole data directory so it can later be restored with
+    /// `restore_snapshot`.
+    ///
+    /// The server is stopped while the copy is made and restarted afterwards.
+    /// This is coarse-grained, but much faster than SQL dumps for large
+    /// amounts of state. Snapshots live inside the server's temporary
+    /// directory and are removed along with it.
+    pub fn snapshot(&mut self) -> Result<Snapshot, PsqlServerError> {
+        self.snapshot_count += 1;
+        let path = self.base_dir.as_ref().unwrap().path().join(format!("snapshot-{}", self.snapshot_count));
+        self.stop()?;
+        let copied = copy_dir(&self.data_dir, &path);
+        if copied.is_err() {
+            // don't leave a partial copy behind
+            let _ = fs::remove_dir_all(&path);
+        }
+        self.restart()?;
+        copied.map_err(PsqlServerError::IoError)?;
+        Ok(Snapshot { path })
+    }
+
+    /// Forces an immediate checkpoint, flushing all dirty buffers to disk.
+    pub fn checkpoint(&self) -> Result<(), PsqlServerError> {
+        self.connect("postgres")?.execute("CHECKPOINT")
+    }
+
+    /// Like `checkpoint`, but also checks with `pg_control_checkpoint` that
+    /// a new checkpoint was recorded, so everything committed before the
+    /// call survives a crash from here on.
+    pub fn checkpoint_and_wait(&self) -> Result<(), PsqlServerError> {
+        let mut connection = self.connect("postgres")?;
+        let sql = "SELECT checkpoint_lsn FROM pg_control_checkpoint()";
+        let lsn = |rows: Vec<Row>| rows.into_iter().next()
+            .and_then(|row| row.into_iter().next())
+            .unwrap_or_default();
+        let before = lsn(connection.query(sql)?);
+        // returns once the checkpoint is complete, and is always forced
+        connection.execute("CHECKPOINT")?;
+        let after = lsn(connection.query(sql)?);
+        if parse_lsn(&after)? > parse_lsn(&before)? {
+            Ok(())
+        } else {
+            Err(PsqlServerError::PsqlFailed(format!(
+                "no new checkpoint was recorded (still at {})", after)))
+        }
+    }
+
+    /// Restarts the server on the same data directory with `params` set,
+    /// for settings like `shared_buffers` or `shared_preload_libraries`
+    /// that only take effect at startup. The params are kept for later
+    /// restarts.
+    pub fn restart_with_config(&mut self, params: &[(&str, &str)]) -> Result<(), PsqlServerError> {
+        for (name, value) in params {
+            self.postgres.set_param(name, value);
+        }
+        self.restart()
+    }
+
+    /// Replaces the data directory with the contents of `snapshot`.
+    ///
+    /// The server is stopped while the data directory is swapped and
+    /// restarted afterwards. A snapshot can be restored any number of times.
+    /// If the copy fails, the server is restarted on its old data.
+    pub fn restore_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), PsqlServerError> {
+        self.stop()?;
+        // copied next to the data directory, so it can be renamed into place
+        let name = self.data_dir.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let restoring = self.data_dir.with_file_name(format!("{}.restoring", name));
+        let old = self.data_dir.with_file_name(format!("{}.old", name));
+        let swapped = remove_dir_if_exists(&restoring)
+            .and_then(|_| remove_dir_if_exists(&old))
+            .and_then(|_| copy_dir(&snapshot.path, &restoring))
+            .and_then(|_| fs::rename(&self.data_dir, &old))
+            .and_then(|_| fs::rename(&restoring, &self.data_dir).inspect_err(|_| {
+                let _ = fs::rename(&old, &self.data_dir);
+            }));
+        if let Err(error) = swapped {
+            let _ = fs::remove_dir_all(&restoring);
+            self.restart()?;
+            return Err(PsqlServerError::IoError(error));
+        }
+        self.restart()?;
+        fs::remove_dir_all(&old)
+            .map_err(PsqlServerError::IoError)
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// The data directory, wherever it lives: in the server's temporary
+    /// directory, a persistent `data_dir` or a custom `DataStore`.
+    pub fn location(&self) -> &Path {
+        &self.data_dir
+    }
+
+    /// The name of the superuser the server was initialised with.
+    pub fn superuser(&self) -> &str {
+        &self.superuser
+    }
+
+    /// The postgres process' stderr lines, as they are logged from now on.
+    /// A stream that falls far behind skips the lines it missed.
+    ///
+    /// ```no_run
+    /// # async fn example() {
+    /// use tokio_stream::StreamExt;
+    /// let server = testing_postgres::PsqlServer::start().unwrap();
+    /// let mut log = server.log_stream();
+    /// server.connect("test").unwrap().execute("CHECKPOINT").unwrap();
+    /// assert!(log.any(|line| line.contains("checkpoint complete")).await);
+    /// # }
+    /// ```
+    #[cfg(feature = "tokio")]
+    pub fn log_stream(&self) -> impl tokio_stream::Stream<Item = String> {
+        use tokio_stream::StreamExt;
+        tokio_stream::wrappers::BroadcastStream::new(self.log.subscribe())
+            .filter_map(|line| line.ok())
+    }
+
+    /// A `tokio_postgres` client connected to `db` as the superuser, with
+    /// its connection driven by a task spawned onto the current runtime.
+    /// The connection doesn't use TLS.
+    #[cfg(feature = "tokio-postgres")]
+    pub async fn tokio_client(&self, db: &str) -> Result<tokio_postgres::Client, PsqlServerError> {
+        let mut config = tokio_postgres::Config::new();
+        config.host(&self.connect_host)
+            .port(self.port)
+            .user(&self.superuser)
+            .dbname(db);
+        if let Some(password) = &self.password {
+            config.password(password);
+        }
+        let (client, connection) = config.connect(tokio_postgres::NoTls).await
+            .map_err(PsqlServerError::Client)?;
+        tokio::spawn(async move {
+            // the client reports the same error on its next request
+            let _ = connection.await;
+        });
+        Ok(client)
+    }
+
+    /// What postgres logged while the server was being started and set up,
+    /// e.g. to check it came up without warnings. Empty when stdio is
+    /// inherited.
+    pub fn startup_log(&self) -> &[String] {
+        &self.startup_log
+    }
+
+    /// The file the server's log is appended to, if `log_file` was set.
+    pub fn log_path(&self) -> Option<&Path> {
+        self.log_path.as_deref()
+    }
+
+    /// Waits until postgres logs a line matching `pattern` (including lines
+    /// it logged before the call), returning the line. Fails with
+    /// `LogNotCaptured` if stdio is inherited.
+    #[cfg(feature = "regex")]
+    pub fn wait_for_log(&self, pattern: &regex::Regex, timeout: Duration) -> Result<String, PsqlServerError> {
+        if self.postgres.inherit_stdio {
+            return Err(PsqlServerError::LogNotCaptured);
+        }
+        self.log.wait_for_line(|line| pattern.is_match(line), timeout)
+            .ok_or_else(|| PsqlServerError::TimedOut(format!("waiting for a log line matching `{}`", pattern)))
+    }
+
+    /// Whether this server's cluster was created by initdb when it started,
+    /// as opposed to reusing an existing `data_dir` or a base backup.
+    pub fn was_initialized(&self) -> bool {
+        self.ran_initdb
+    }
+
+    /// The superuser's password, if one was set or generated.
+    pub fn password(&self) -> Option<&str> {
+        self.password.as_deref()
+    }
+
+    /// A `postgres://` URL for connecting to the default database as the
+    /// superuser.
+    pub fn connection_string(&self) -> String {
+        self.url(&self.connection_options)
+    }
+
+    /// Like `connection_string`, but with `default_transaction_read_only`
+    /// turned on, e.g. for routing reads to a standby.
+    pub fn read_only_connection_string(&self) -> String {
+        let mut options = self.connection_options.clone();
+        let read_only = "-c default_transaction_read_only=on";
+        match options.iter_mut().find(|(key, _)| key == "options") {
+            Some((_, value)) => value.push_str(&format!(" {}", read_only)),
+            None => options.push(("options".to_owned(), read_only.to_owned()))
+        }
+        self.url(&options)
+    }
+
+    fn url(&self, options: &[(String, String)]) -> String {
+        let password = match &self.password {
+            Some(password) => format!(":{}", percent_encode(password)),
+            None => String::new()
+        };
+        let query = options.iter()
+            .map(|(key, value)| format!("{}={}", percent_encode(key), percent_encode(value)))
+            .collect::<Vec<_>>()
+            .join("&");
+        let query = if query.is_empty() { query } else { format!("?{}", query) };
+        format!("postgres://{}{}@{}:{}/{}{}",
+                percent_encode(&self.superuser), password, url_host(&self.connect_host), self.port,
+                percent_encode(&self.database), query)
+    }
+
+    /// The libpq environment variables (`PGHOST`, `PGPORT`, ...) for
+    /// connecting to the default database as the superuser.
+    pub fn env_vars(&self) -> Vec<(&'static str, String)> {
+        let mut vars = self.libpq_env_vars(&self.connect_host);
+        if let Some((_, sslmode)) = self.connection_options.iter().find(|(key, _)| key == "sslmode") {
+            vars.push(("PGSSLMODE", sslmode.clone()));
+        }
+        vars
+    }
+
+    /// Like `env_vars`, but with `PGHOST` pointing at the server's unix
+    /// socket directory, which is faster than TCP for local tools. The
+    /// same as `env_vars` on windows, where there is no socket.
+    pub fn socket_env_vars(&self) -> Vec<(&'static str, String)> {
+        if cfg!(windows) {
+            return self.env_vars();
+        }
+        // libpq never uses TLS over a socket, so there is no PGSSLMODE
+        self.libpq_env_vars(&self.socket_path.to_string_lossy())
+    }
+
+    fn libpq_env_vars(&self, host: &str) -> Vec<(&'static str, String)> {
+        let mut vars = vec![
+            ("PGHOST", host.to_owned()),
+            ("PGPORT", format!("{}", self.port)),
+            ("PGUSER", self.superuser.clone()),
+            ("PGDATABASE", self.database.clone())];
+        if let Some(password) = &self.password {
+            vars.push(("PGPASSWORD", password.clone()));
+        }
+        vars
+    }
+
+    /// Writes `DATABASE_URL` and the `env_vars` to `path` as a `.env` file
+    /// for other processes to pick up. The file is only readable by its
+    /// owner (on unix), as it may contain the password.
+    pub fn write_env_file(&self, path: &Path) -> Result<(), PsqlServerError> {
+        let mut contents = format!("DATABASE_URL={}\n", dotenv_value(&self.connection_string()));
+        for (name, value) in self.env_vars() {
+            contents.push_str(&format!("{}={}\n", name, dotenv_value(&value)));
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path)
+            .map_err(PsqlServerError::IoError)?;
+        // the mode only applies to newly created files
+        restrict_to_owner(path)?;
+        std::io::Write::write_all(&mut file, contents.as_bytes())
+            .map_err(PsqlServerError::IoError)
+    }
+
+    /// How long starting the server took.
+    pub fn timings(&self) -> StartupTimings {
+        self.timings
+    }
+
+    /// Writes a `/bin/sh` script to `path` that sets up a cluster the way
+    /// `start` set up this one: the same initdb, postgres and createdb
+    /// command lines, followed by the roles, extensions and psql scripts.
+    /// The cluster goes to `/tmp/testing-postgres-repro` on this server's
+    /// port unless `BASE_DIR`, `DATA_DIR` or `PORT` are set. Migration hooks,
+    /// certificates and a `config_file` aren't reproduced.
+    pub fn export_repro_script(&self, path: &Path) -> Result<(), PsqlServerError> {
+        let script = format!(
+            "#!/bin/sh\n\
+             # Reproduces the setup of a testing-postgres server; postgres keeps\n\
+             # running in the foreground once it is done.\n\
+             set -e\n{}\n", self.repro_script);
+        fs::write(path, script)
+            .map_err(PsqlServerError::IoError)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+                .map_err(PsqlServerError::IoError)?;
+        }
+        Ok(())
+    }
+
+    /// Where the server can be reached.
+    pub fn endpoint(&self) -> Endpoint {
+        Endpoint {
+            host: self.connect_host.clone(),
+            port: self.port,
+            socket_dir: if cfg!(unix) { Some(self.socket_path.clone()) } else { None }
+        }
+    }
+
+    /// The settings the server runs with, as resolved from the builder and
+    /// any `restart_with_config` since.
+    pub fn resolved_config(&self) -> ResolvedConfig {
+        let args = &self.postgres.args;
+        let arg_after = |flag: &str| args.iter().position(|arg| arg == flag)
+            .and_then(|i| args.get(i + 1))
+            .cloned()
+            .unwrap_or_default();
+        let settings = args.windows(2)
+            .filter(|pair| pair[0] == "-c")
+            .filter_map(|pair| pair[1].split_once('='))
+            .map(|(name, value)| (name.to_owned(), value.to_owned()))
+            .collect();
+        ResolvedConfig {
+            postgres: PathBuf::from(&self.postgres.program),
+            pg_ctl: self.postgres.pg_ctl.as_ref().map(PathBuf::from),
+            pg_isready: PathBuf::from(&self.pg_isready),
+            bin_dir: self.bin_dir.clone(),
+            version_num: self.version_num,
+            lifecycle: if self.postgres.pg_ctl.is_some() { Lifecycle::PgCtl } else { Lifecycle::Direct },
+            data_dir: self.data_dir.clone(),
+            socket_dir: self.socket_path.clone(),
+            port: self.port,
+            listen_addresses: arg_after("-h"),
+            connect_host: self.connect_host.clone(),
+            superuser: self.superuser.clone(),
+            auth_method: self.auth_method,
+            database: self.database.clone(),
+            settings,
+            args: args.clone()
+        }
+    }
+
+    /// Everything needed to connect to the default database as the
+    /// superuser.
+    pub fn descriptor(&self) -> ServerDescriptor {
+        ServerDescriptor {
+            endpoint: self.endpoint(),
+            user: self.superuser.clone(),
+            password: self.password.clone(),
+            database: self.database.clone(),
+            url: self.connection_string()
+        }
+    }
+
+    /// The server's version in `server_version_num` form, e.g. `150004`.
+    pub fn version_num(&self) -> u32 {
+        self.version_num
+    }
+
+    /// The version of the `psql` the crate connects with, e.g. `15.4` (or
+    /// `17beta1`), which can differ from the server's. Its libpq usually
+    /// has the same version.
+    pub fn client_version(&self) -> Result<String, PsqlServerError> {
+        let (psql, _) = self.psql_binary()?;
+        binary_version(psql)
+    }
+
+    /// `client_version` in `server_version_num` form, for comparisons.
+    pub fn client_version_num(&self) -> Result<u32, PsqlServerError> {
+        self.psql_binary().map(|&(_, version)| version)
+    }
+
+    /// Whether the server is new enough to support `feature`.
+    pub fn supports(&self, feature: PgFeature) -> bool {
+        self.version_num >= feature.min_version_num()
+    }
+
+    /// Whether the files for extension `name` are installed, so that
+    /// `CREATE EXTENSION` can work, e.g. to skip tests needing `postgis`.
+    pub fn extension_available(&self, name: &str) -> Result<bool, PsqlServerError> {
+        let count = self.query_value("postgres", &format!(
+            "SELECT count(*) FROM pg_available_extensions WHERE name = {}", quote_literal(name)))?;
+        Ok(count.trim() != "0")
+    }
+
+    /// The name and default version of every extension whose files are
+    /// installed, sorted by name.
+    pub fn available_extensions(&self) -> Result<Vec<(String, String)>, PsqlServerError> {
+        self.query_pairs("postgres",
+            "SELECT name, default_version FROM pg_available_extensions ORDER BY name")
+    }
+
+    /// The name and version of every extension created in `db`, sorted by
+    /// name.
+    pub fn installed_extensions(&self, db: &str) -> Result<Vec<(String, String)>, PsqlServerError> {
+        self.query_pairs(db, "SELECT extname, extversion FROM pg_extension ORDER BY extname")
+    }
+
+    /// The first two columns of each row `sql` returns in `db`.
+    fn query_pairs<C: std::iter::FromIterator<(String, String)>>(&self, db: &str, sql: &str) -> Result<C, PsqlServerError> {
+        let rows = self.connect(db)?.query(sql)?;
+        Ok(rows.into_iter()
+           .filter_map(|row| {
+               let mut columns = row.into_iter();
+               Some((columns.next()?, columns.next()?))
+           })
+           .collect())
+    }
+
+    /// The plan of `sql` in `db`, parsed from `EXPLAIN (FORMAT JSON)`.
+    #[cfg(feature = "serde")]
+    pub fn explain(&self, db: &str, sql: &str) -> Result<serde_json::Value, PsqlServerError> {
+        let plan = self.query_value(db, &format!("EXPLAIN (FORMAT JSON) {}", sql))?;
+        serde_json::from_str(&plan)
+            .map_err(|error| PsqlServerError::PsqlFailed(format!("could not parse the plan: {}", error)))
+    }
+
+    /// Opens a connection to `db` as the superuser. While the server is
+    /// starting up or recovering (SQLSTATE 57P03 or 57P02), this keeps
+    /// retrying until the startup timeout.
+    pub fn connect(&self, db: &str) -> Result<PsqlConnection, PsqlServerError> {
+        let &(_, version) = self.psql_binary()?;
+        // the connection relies on `\warn`, added in psql 13
+        if version < 130000 {
+            return Err(PsqlServerError::PsqlFailed(
+                format!("connect() needs psql 13 or newer, found {}", major_version(version))));
+        }
+        let deadline = Instant::now() + self.postgres.startup_timeout;
+        loop {
+            let error = match PsqlConnection::open(self.psql_command(db)?) {
+                Ok(connection) => return Ok(connection),
+                Err(error) => error
+            };
+            // a crash explains the failure better than psql does
+            self.check_running()?;
+            match error {
+                PsqlServerError::PsqlFailed(message)
+                    if connection::is_cannot_connect_now(&message) && Instant::now() < deadline =>
+                    thread::sleep(Duration::from_millis(100)),
+                error => return Err(error)
+            }
+        }
+    }
+
+    /// Runs `sql` against `db` on a new connection, returning the first value
+    /// of the first row, or an empty string if there is none.
+    fn query_value(&self, db: &str, sql: &str) -> Result<String, PsqlServerError> {
+        let rows = self.connect(db)?.query(sql)?;
+        Ok(rows.into_iter().next()
+           .and_then(|row| row.into_iter().next())
+           .unwrap_or_default())
+    }
+
+    /// Creates the login role `name` unless it exists.
+    fn create_role(&self, name: &str) -> Result<(), PsqlServerError> {
+        let exists = self.query_value("postgres", &format!(
+            "SELECT count(*) FROM pg_roles WHERE rolname = {}", quote_literal(name)))?;
+        if exists.trim() != "0" {
+            return Ok(());
+        }
+        let password = match &self.password {
+            Some(password) => format!(" PASSWORD {}", quote_literal(password)),
+            None => String::new()
+        };
+        self.connect("postgres")?
+            .execute(&format!("CREATE ROLE {} LOGIN{}", quote_ident(name), password))
+    }
+
+    fn psql_binary(&self) -> Result<&(String, u32), PsqlServerError> {
+        if let Some(psql) = self.psql.get() {
+            return Ok(psql);
+        }
+        let psql = find_tool("psql", self.bin_dir.as_deref())
+            .map_err(|_| PsqlServerError::CouldNotFindPsqlCommand)?;
+        let version = binary_version_num(&psql)?;
+        Ok(self.psql.get_or_init(|| (psql, version)))
+    }
+
+    /// A client tool command that authenticates as the superuser.
+    fn tool_command<S: AsRef<std::ffi::OsStr>>(&self, program: S) -> Command {
+        let mut command = client_command(program);
+        if let Some(password) = &self.password {
+            command.env("PGPASSWORD", password);
+        }
+        command
+    }
+
+    /// A `psql` command connected to `db` that stops on the first error.
+    fn psql_command(&self, db: &str) -> Result<Command, PsqlServerError> {
+        self.check_running()?;
+        let (psql, _) = self.psql_binary()?;
+        let mut command = self.tool_command(psql);
+        command.args(["-X", "-q",
+                      "-v", "ON_ERROR_STOP=1",
+                      "-p", &format!("{}", self.port),
+                      "-h", &self.local_host,
+                      "-U", &self.superuser,
+                      "-d", db]);
+        Ok(command)
+    }
+
+    fn run_psql_script(&self, file: &Path) -> Result<(), PsqlServerError> {
+        let out = self.psql_command(&self.database)?
+            .arg("-f").arg(file)
+            .output()
+            .map_err(PsqlServerError::IoError)?;
+        if !out.status.success() {
+            return Err(PsqlServerError::PsqlScriptFailed {
+                file: file.to_owned(),
+                code: out.status.code(),
+                stderr: String::from_utf8_lossy(&out.stderr).trim().to_owned()
+            });
+        }
+        Ok(())
+    }
+
+    /// Applies the `.sql` files in `dir` to `db` in lexicographic order, all
+    /// in a single transaction.
+    pub fn run_migrations_dir(&self, db: &str, dir: &Path) -> Result<(), PsqlServerError> {
+        self.run_migrations_dir_with(db, dir, MigrationTransaction::Single)
+    }
+
+    /// Like `run_migrations_dir`, choosing how the files are wrapped in
+    /// transactions. Stops at the first file that fails.
+    pub fn run_migrations_dir_with(&self, db: &str, dir: &Path,
+                                   transaction: MigrationTransaction) -> Result<(), PsqlServerError> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir).map_err(PsqlServerError::IoError)? {
+            let path = entry.map_err(PsqlServerError::IoError)?.path();
+            if path.is_file() && path.extension() == Some("sql".as_ref()) {
+                files.push(path);
+            }
+        }
+        files.sort();
+
+        let batches: Vec<&[PathBuf]> = match transaction {
+            MigrationTransaction::Single => vec![&files[..]],
+            MigrationTransaction::PerFile => files.chunks(1).collect()
+        };
+        for batch in batches {
+            if batch.is_empty() {
+                continue;
+            }
+            let mut command = self.psql_command(db)?;
+            command.arg("--single-transaction");
+            for file in batch {
+                command.arg("-f").arg(file);
+            }
+            let out = command.output()
+                .map_err(PsqlServerError::IoError)?;
+            if !out.status.success() {
+                let stderr = String::from_utf8_lossy(&out.stderr).trim().to_owned();
+                // psql prefixes errors with `psql:<file>:<line>:`
+                let file = batch.iter()
+                    .find(|file| stderr.contains(&format!("psql:{}:", file.display())))
+                    .unwrap_or(&batch[0]);
+                return Err(PsqlServerError::MigrationFileFailed {
+                    file: file.clone(),
+                    stderr
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads a CSV file with a header row into `table` using `\copy`, which
+    /// is far faster than inserting row by row, and returns the number of
+    /// rows loaded. `table` is passed through as is, so it may be schema
+    /// qualified or include a column list.
+    pub fn copy_from_csv(&self, db: &str, table: &str, csv_path: &Path) -> Result<u64, PsqlServerError> {
+        let out = self.psql_command(db)?
+            // without QUIET off psql doesn't print the `COPY n` tag
+            .args(["-v", "QUIET=off", "-c"])
+            .arg(format!("\\copy {} FROM {} CSV HEADER",
+                         table, quote_literal(&csv_path.to_string_lossy())))
+            .output()
+            .map_err(PsqlServerError::IoError)?;
+        if !out.status.success() {
+            return Err(PsqlServerError::PsqlFailed(
+                String::from_utf8_lossy(&out.stderr).trim().to_owned()));
+        }
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        stdout.lines()
+            .find_map(|line| line.trim().strip_prefix("COPY ")?.parse().ok())
+            .ok_or_else(|| PsqlServerError::PsqlFailed(
+                format!("unexpected output from \\copy: {}", stdout.trim())))
+    }
+
+    /// Every setting of the server as `SHOW ALL` reports it, i.e. with
+    /// units such as `128MB`, e.g. to check a `config_param` wasn't
+    /// clamped.
+    pub fn show_all(&self) -> Result<HashMap<String, String>, PsqlServerError> {
+        self.query_pairs("postgres", "SHOW ALL")
+    }
+
+    /// The current value of the setting `key`, like `SHOW key`. Fails if
+    /// there is no such setting.
+    pub fn show(&self, key: &str) -> Result<String, PsqlServerError> {
+        self.query_value("postgres", &format!("SELECT current_setting({})", quote_literal(key)))
+    }
+
+    /// The on-disk size of `db` in bytes, from `pg_database_size`.
+    pub fn database_size(&self, db: &str) -> Result<u64, PsqlServerError> {
+        self.size_query(&format!("SELECT pg_database_size({})", quote_literal(db)))
+    }
+
+    /// The on-disk size of all databases together in bytes.
+    pub fn cluster_size(&self) -> Result<u64, PsqlServerError> {
+        self.size_query("SELECT sum(pg_database_size(oid)) FROM pg_database")
+    }
+
+    fn size_query(&self, sql: &str) -> Result<u64, PsqlServerError> {
+        let size = self.query_value("postgres", sql)?;
+        size.trim().parse()
+            .map_err(|_| PsqlServerError::PsqlFailed(format!("unexpected size `{}`", size.trim())))
+    }
+
+    /// Restarts every sequence in `db` at its start value, so generated IDs
+    /// are the same from test to test.
+    pub fn reset_sequences(&self, db: &str) -> Result<(), PsqlServerError> {
+        self.reset_sequences_with(db, &[])
+    }
+
+    /// Like `reset_sequences`, restarting the sequences in `starts` (named
+    /// `schema.sequence`, or just `sequence`) at the given value instead.
+    pub fn reset_sequences_with(&self, db: &str, starts: &[(&str, i64)]) -> Result<(), PsqlServerError> {
+        let mut connection = self.connect(db)?;
+        // rather than pg_sequences, which needs 10
+        let sequences = connection.query(
+            "SELECT n.nspname, c.relname FROM pg_class c
+             JOIN pg_namespace n ON n.oid = c.relnamespace
+             WHERE c.relkind = 'S'")?;
+        let statements: Vec<String> = sequences.iter()
+            .filter_map(|row| match row.as_slice() {
+                [schema, name] => Some((schema, name)),
+                _ => None
+            })
+            .map(|(schema, name)| {
+                let qualified = format!("{}.{}", schema, name);
+                let start = starts.iter()
+                    .find(|(sequence, _)| *sequence == qualified || sequence == name)
+                    .map(|(_, start)| format!(" WITH {}", start))
+                    .unwrap_or_default();
+                format!("ALTER SEQUENCE {}.{} RESTART{};", quote_ident(schema), quote_ident(name), start)
+            })
+            .collect();
+        if statements.is_empty() {
+            return Ok(());
+        }
+        connection.execute(&statements.join("\n"))
+    }
+
+    /// Runs `sql` in `db` unless a fixture named `key` was already applied
+    /// there, returning whether it ran. Applied keys are recorded in a
+    /// `__testing_fixtures` table, in the same transaction as `sql`.
+    pub fn ensure_fixture_applied(&self, db: &str, key: &str, sql: &str) -> Result<bool, PsqlServerError> {
+        let mut connection = self.connect(db)?;
+        connection.execute("CREATE TABLE IF NOT EXISTS __testing_fixtures (
+                                key text PRIMARY KEY,
+                                applied_at timestamptz NOT NULL DEFAULT now())")?;
+        // the lock keeps concurrent callers from applying the fixture twice
+        connection.execute("BEGIN; LOCK TABLE __testing_fixtures IN EXCLUSIVE MODE")?;
+        let applied = connection.query(&format!(
+            "SELECT count(*) FROM __testing_fixtures WHERE key = {}", quote_literal(key)))?;
+        if applied.first().and_then(|row| row.first()).is_some_and(|count| count != "0") {
+            connection.execute("COMMIT")?;
+            return Ok(false);
+        }
+        // on error, dropping the connection rolls everything back
+        connection.execute(&format!(
+            "{}\n;\nINSERT INTO __testing_fixtures (key) VALUES ({});\nCOMMIT", sql, quote_literal(key)))?;
+        Ok(true)
+    }
+
+    /// The DDL of `db` as plain SQL from `pg_dump --schema-only`, e.g. for
+    /// comparing a migrated schema against a golden file.
+    pub fn dump_schema(&self, db: &str) -> Result<String, PsqlServerError> {
+        self.pg_dump(db, "--schema-only")
+    }
+
+    /// The rows of `db` as plain SQL from `pg_dump --data-only`.
+    pub fn dump_data(&self, db: &str) -> Result<String, PsqlServerError> {
+        self.pg_dump(db, "--data-only")
+    }
+
+    /// Checks that `down_sql` undoes `up_sql`: applies both to `db` in turn
+    /// and fails with `IrreversibleMigration` if the schema dump afterwards
+    /// differs from the one before.
+    pub fn assert_reversible_migration(&self, db: &str, up_sql: &str, down_sql: &str) -> Result<(), PsqlServerError> {
+        // recent pg_dumps fence their output with a random `\restrict` key
+        let dump = || self.dump_schema(db).map(|dump| dump.lines()
+            .filter(|line| !line.starts_with("\\restrict ") && !line.starts_with("\\unrestrict "))
+            .collect::<Vec<_>>()
+            .join("\n"));
+        let before = dump()?;
+        self.connect(db)?.execute(up_sql)?;
+        self.connect(db)?.execute(down_sql)?;
+        let after = dump()?;
+        if before == after {
+            Ok(())
+        } else {
+            Err(PsqlServerError::IrreversibleMigration(line_diff(&before, &after)))
+        }
+    }
+
+    fn pg_dump(&self, db: &str, section: &str) -> Result<String, PsqlServerError> {
+        self.check_running()?;
+        let pg_dump = find_tool("pg_dump", self.bin_dir.as_deref())
+            .map_err(|_| PsqlServerError::CouldNotFindPgDumpCommand)?;
+        let out = self.tool_command(pg_dump)
+            .args([section,
+                   "-p", &self.port.to_string(),
+                   "-h", &self.local_host,
+                   "-U", &self.superuser,
+                   "-d", db])
+            .output()
+            .map_err(PsqlServerError::IoError)?;
+        if !out.status.success() {
+            return Err(PsqlServerError::PgDumpFailed(
+                String::from_utf8_lossy(&out.stderr).trim().to_owned()));
+        }
+        Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+    }
+
+    /// The server's current WAL write location, e.g. `0/3000148`.
+    pub fn current_wal_lsn(&self) -> Result<String, PsqlServerError> {
+        self.query_value("postgres", "SELECT pg_current_wal_lsn()")
+    }
+
+    /// Clones this server into `data_dir` with `pg_basebackup`, set up to
+    /// run as its standby, connecting as `application_name` (which needs no
+    /// conninfo quoting).
+    fn clone_standby(&self, data_dir: &str, application_name: &str) -> Result<(), PsqlServerError> {
+        let pg_basebackup = find_tool("pg_basebackup", self.bin_dir.as_deref())
+            .map_err(|_| PsqlServerError::CouldNotFindPgBasebackupCommand)?;
+        let out = self.tool_command(pg_basebackup)
+            .args(["-h", &self.local_host, "-p", &self.port.to_string(), "-U", &self.superuser,
+                   "-D", data_dir, "-R", "-X", "stream", "-c", "fast",
+                   // ends up in the standby's primary_conninfo
+                   "-d", &format!("application_name={}", application_name)])
+            .output()
+            .map_err(PsqlServerError::IoError)?;
+        if !out.status.success() {
+            return Err(PsqlServerError::BaseBackupFailed(
+                String::from_utf8_lossy(&out.stderr).trim().to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Appends `name` to `synchronous_standby_names` and waits for the
+    /// standby to be streaming as `sync`, or as `potential` if a standby
+    /// listed before it already is.
+    fn add_synchronous_standby(&self, name: &str, timeout: Duration) -> Result<(), PsqlServerError> {
+        let names = self.show("synchronous_standby_names")?;
+        let names = if names.is_empty() {
+            quote_ident(name)
+        } else {
+            format!("{}, {}", names, quote_ident(name))
+        };
+        let mut connection = self.connect("postgres")?;
+        connection.execute(&format!("ALTER SYSTEM SET synchronous_standby_names = {}", quote_literal(&names)))?;
+        connection.execute("SELECT pg_reload_conf()")?;
+        let sql = format!(
+            "SELECT count(*) FROM pg_stat_replication
+             WHERE application_name = {} AND sync_state IN ('sync', 'potential')", quote_literal(name));
+        let deadline = Instant::now() + timeout;
+        loop {
+            if connection.query(&sql)?.first().and_then(|row| row.first()).is_some_and(|count| count != "0") {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(PsqlServerError::TimedOut(
+                    format!("waiting for standby `{}` to become synchronous", name)));
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Waits until the test database's commit count in `pg_stat_database`
+    /// goes up. Every check runs in a session of its own, whose commit is
+    /// reported when it exits, so this waits for the statistics to catch up
+    /// rather than for the server to flush them on its own schedule.
+    fn wait_for_stats(&self, timeout: Duration) -> Result<(), PsqlServerError> {
+        let sql = format!("SELECT xact_commit FROM pg_stat_database WHERE datname = {}",
+                          quote_literal(&self.database));
+        let first = self.query_value(&self.database, &sql)?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.query_value(&self.database, &sql)? != first {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(PsqlServerError::TimedOut("waiting for the statistics to be updated".to_owned()));
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Waits until this server (a standby) has replayed WAL up to at least
+    /// `lsn`, typically obtained from `current_wal_lsn` on the primary.
+    pub fn wait_for_replay_lsn(&self, lsn: &str, timeout: Duration) -> Result<(), PsqlServerError> {
+        let target = parse_lsn(lsn)?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let replayed = self.query_value("postgres", "SELECT pg_last_wal_replay_lsn()")?;
+            let replayed = replayed.as_str();
+            if replayed.is_empty() {
+                return Err(PsqlServerError::NotAStandby);
+            }
+            if parse_lsn(replayed)? >= target {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(PsqlServerError::TimedOut(
+                    format!("waiting for replay of {} (replayed up to {})", lsn, replayed)));
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Terminates every other backend connected to `db`, returning how many
+    /// were terminated.
+    pub fn terminate_connections(&self, db: &str) -> Result<usize, PsqlServerError> {
+        let count = self.query_value("postgres", &format!(
+            "SELECT count(*) FROM (
+                 SELECT pg_terminate_backend(pid) AS terminated
+                 FROM pg_stat_activity
+                 WHERE datname = {} AND pid <> pg_backend_pid()
+             ) t WHERE terminated", quote_literal(db)))?;
+        Ok(count.trim().parse().unwrap_or(0))
+    }
+
+    /// Drops the database `db`, see `DropDatabase` for options.
+    pub fn drop_database<'a>(&'a self, db: &'a str) -> DropDatabase<'a> {
+        DropDatabase { server: self, db, force: false }
+    }
+
+    /// Enables row-level security on `table` in `db` (named `schema.table`,
+    /// or just `table`) and forces it, so its policies apply to the table's
+    /// owner too. Superusers still bypass them; see `role` for running
+    /// queries as someone else.
+    pub fn force_rls(&self, db: &str, table: &str) -> Result<(), PsqlServerError> {
+        let name: Vec<String> = table.split('.').map(quote_ident).collect();
+        self.connect(db)?.execute(&format!(
+            "ALTER TABLE {} ENABLE ROW LEVEL SECURITY, FORCE ROW LEVEL SECURITY", name.join(".")))
+    }
+
+    /// Creates the schema `schema` in `db`.
+    pub fn create_schema(&self, db: &str, schema: &str) -> Result<(), PsqlServerError> {
+        self.connect(db)?
+            .execute(&format!("CREATE SCHEMA {}", quote_ident(schema)))
+    }
+
+    /// Drops the schema `schema` from `db`, along with everything in it if
+    /// `cascade` is set.
+    pub fn drop_schema(&self, db: &str, schema: &str, cascade: bool) -> Result<(), PsqlServerError> {
+        self.connect(db)?
+            .execute(&format!("DROP SCHEMA {}{}", quote_ident(schema),
+                              if cascade { " CASCADE" } else { "" }))
+    }
+
+    /// Creates the schema `schema` in `db` and drops it again when the
+    /// returned guard goes out of scope, a lighter way to isolate tests than
+    /// a database each.
+    pub fn scoped_schema(&self, db: &str, schema: &str) -> Result<SchemaGuard<'_>, PsqlServerError> {
+        self.create_schema(db, schema)?;
+        Ok(SchemaGuard { server: self, db: db.to_owned(), schema: schema.to_owned() })
+    }
+
+    /// Waits until a backend whose `backend_type` or `application_name` is
+    /// `name` shows up in `pg_stat_activity`, e.g. an extension's background
+    /// worker such as `pg_cron launcher`, which may start some time after
+    /// the server accepts connections.
+    pub fn wait_for_bgworker(&self, name: &str, timeout: Duration) -> Result<(), PsqlServerError> {
+        let mut connection = self.connect("postgres")?;
+        let sql = format!(
+            "SELECT count(*) FROM pg_stat_activity WHERE backend_type = {0} OR application_name = {0}",
+            quote_literal(name));
+        let deadline = Instant::now() + timeout;
+        loop {
+            let rows = connection.query(&sql)?;
+            if rows.first().is_some_and(|row| row[0] != "0") {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(PsqlServerError::TimedOut(
+                    format!("waiting for background worker `{}`", name)));
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Polls the row count of `table` in `db` until `predicate` accepts it,
+    /// returning the count, e.g. to wait for a background job to fill a
+    /// table. `table` may be qualified as `schema.table`; each part is
+    /// quoted as an identifier.
+    pub fn wait_for_row_count<F: Fn(i64) -> bool>(&self, db: &str, table: &str, predicate: F,
+                                                 timeout: Duration) -> Result<i64, PsqlServerError> {
+        let mut connection = self.connect(db)?;
+        let name: Vec<String> = table.split('.').map(quote_ident).collect();
+        let sql = format!("SELECT count(*) FROM {}", name.join("."));
+        let deadline = Instant::now() + timeout;
+        loop {
+            let rows = connection.query(&sql)?;
+            let count = rows.first()
+                .and_then(|row| row.first())
+                .and_then(|count| count.parse().ok())
+                .ok_or_else(|| PsqlServerError::PsqlFailed(format!("unexpected count: {:?}", rows)))?;
+            if predicate(count) {
+                return Ok(count);
+            }
+            if Instant::now() >= deadline {
+                return Err(PsqlServerError::TimedOut(
+                    format!("waiting for the row count of `{}` (last {})", table, count)));
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Counts the client connections to the server by state, not including
+    /// the connection used to take the snapshot, e.g. to catch leaked
+    /// transactions with `activity()?.idle_in_transaction == 0`.
+    pub fn activity(&self) -> Result<ActivitySnapshot, PsqlServerError> {
+        let rows = self.connect("postgres")?.query(
+            "SELECT count(*),
+                    count(*) FILTER (WHERE state = 'active'),
+                    count(*) FILTER (WHERE state = 'idle'),
+                    count(*) FILTER (WHERE state LIKE 'idle in transaction%')
+             FROM pg_stat_activity
+             WHERE backend_type = 'client backend' AND pid <> pg_backend_pid()")?;
+        let counts: Vec<usize> = rows.first()
+            .map(|row| row.iter().map(|count| count.parse().unwrap_or(0)).collect())
+            .unwrap_or_default();
+        match counts.as_slice() {
+            &[total_backends, active, idle, idle_in_transaction] =>
+                Ok(ActivitySnapshot { total_backends, active, idle, idle_in_transaction }),
+            _ => Err(PsqlServerError::PsqlFailed(
+                format!("unexpected pg_stat_activity counts: {:?}", rows)))
+        }
+    }
+
+    /// The `n` statements with the highest total execution time, from
+    /// `pg_stat_statements` (see `PsqlServerBuilder::pg_stat_statements`).
+    pub fn top_statements(&self, n: usize) -> Result<Vec<StatementStat>, PsqlServerError> {
+        // renamed in 13, when planning time got tracked separately
+        let total_time = if self.version_num >= 130000 { "total_exec_time" } else { "total_time" };
+        let rows = self.connect(&self.database)?.query(&format!(
+            "SELECT query, calls, {}, rows FROM pg_stat_statements ORDER BY 3 DESC LIMIT {}",
+            total_time, n))?;
+        rows.into_iter().map(|row| match row.as_slice() {
+            [query, calls, total_time, rows] => Ok(StatementStat {
+                query: query.clone(),
+                calls: calls.parse().unwrap_or(0),
+                total_time: Duration::from_secs_f64(total_time.parse::<f64>().unwrap_or(0.0) / 1000.0),
+                rows: rows.parse().unwrap_or(0)
+            }),
+            _ => Err(PsqlServerError::PsqlFailed(format!("unexpected pg_stat_statements row: {:?}", row)))
+        }).collect()
+    }
+
+    /// Drops every database except `postgres`, `template0` and `template1`
+    /// (including `test`), returning the cluster to its pristine state.
+    /// Connections to them are refused and terminated first.
+    pub fn reset_cluster(&self) -> Result<(), PsqlServerError> {
+        let mut connection = self.connect("postgres")?;
+        let databases = connection.query(
+            "SELECT datname FROM pg_database
+             WHERE datname NOT IN ('postgres', 'template0', 'template1')")?;
+        for row in databases {
+            let db = &row[0];
+            connection.execute(&format!("ALTER DATABASE {} WITH ALLOW_CONNECTIONS false IS_TEMPLATE false",
+                                        quote_ident(db)))?;
+            self.terminate_connections(db)?;
+            connection.execute(&format!("DROP DATABASE {}", quote_ident(db)))?;
+        }
+        Ok(())
+    }
+
+    /// Opens `n` connections to the `test` database and waits (up to the
+    /// `startup_timeout`) until the server lists all of them in
+    /// `pg_stat_activity`, failing with `TimedOut` otherwise.
+    ///
+    /// Each connection is held by an idle `psql` process; pass them to
+    /// `drain` to close them deterministically.
+    pub fn open_connections(&self, n: usize) -> Result<Vec<Connection>, PsqlServerError> {
+        let (psql, _) = self.psql_binary()?;
+        let mut connections = Vec::with_capacity(n);
+        for _ in 0..n {
+            let application_name = format!(
+                "testing-postgres-{}-{}", std::process::id(),
+                NEXT_CONNECTION_ID.fetch_add(1, Ordering::SeqCst));
+            let process = self.tool_command(psql)
+                .args(["-X", "-q",
+                       "-p", &format!("{}", self.port),
+                       "-h", &self.local_host,
+                       "-U", &self.superuser,
+                       "-d", &self.database])
+                .env("PGAPPNAME", &application_name)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::piped())
+                .spawn()
+                .map_err(PsqlServerError::IoError)?;
+            connections.push(Connection { process, application_name });
+        }
+
+        // on any error the connections opened so far are dropped, which
+        // kills their psql processes
+        let deadline = Instant::now() + self.postgres.startup_timeout;
+        loop {
+            for connection in &mut connections {
+                if connection.process.try_wait()
+                    .map_err(PsqlServerError::IoError)?.is_some() {
+                        let mut stderr = String::new();
+                        if let Some(mut pipe) = connection.process.stderr.take() {
+                            let _ = pipe.read_to_string(&mut stderr);
+                        }
+                        return Err(PsqlServerError::PsqlFailed(stderr.trim().to_owned()));
+                    }
+            }
+            if self.count_connections(&connections)? == connections.len() {
+                return Ok(connections);
+            }
+            if Instant::now() >= deadline {
+                return Err(PsqlServerError::TimedOut(
+                    format!("waiting for {} connections to show up in pg_stat_activity", n)));
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Closes `connections` and waits (up to the `startup_timeout`) until
+    /// the server no longer lists any of them in `pg_stat_activity`,
+    /// failing with `TimedOut` otherwise.
+    pub fn drain(&self, mut connections: Vec<Connection>) -> Result<(), PsqlServerError> {
+        for connection in &mut connections {
+            // psql exits once its stdin is closed
+            connection.process.stdin.take();
+            connection.process.wait()
+                .map_err(PsqlServerError::IoError)?;
+        }
+        let deadline = Instant::now() + self.postgres.startup_timeout;
+        while self.count_connections(&connections)? > 0 {
+            if Instant::now() >= deadline {
+                return Err(PsqlServerError::TimedOut(
+                    "waiting for closed connections to leave pg_stat_activity".to_owned()));
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        Ok(())
+    }
+
+    fn count_connections(&self, connections: &[Connection]) -> Result<usize, PsqlServerError> {
+        if connections.is_empty() {
+            return Ok(0);
+        }
+        let names: Vec<String> = connections.iter()
+            .map(|connection| quote_literal(&connection.application_name))
+            .collect();
+        let count = self.query_value("postgres", &format!(
+            "SELECT count(*) FROM pg_stat_activity WHERE application_name IN ({})",
+            names.join(", ")))?;
+        Ok(count.trim().parse().unwrap_or(0))
+    }
+
+    fn stop(&mut self) -> Result<(), PsqlServerError> {
+        if let Some(pg_ctl) = &self.postgres.pg_ctl {
+            if !self.postgres.pg_ctl_running(pg_ctl) {
+                return Ok(());
+            }
+            return self.postgres.pg_ctl_stop(pg_ctl, "fast")
+                .map_err(PsqlServerError::IoError);
+        }
+        // taken out first, so the crash monitor knows the exit is expected
+        let process = self.child().take();
+        if let Some(mut process) = process {
+            shutdown(&mut process)
+                .map_err(PsqlServerError::IoError)?;
+        }
+        Ok(())
+    }
+
+    /// Freezes postgres and all its processes with SIGSTOP (unix only), for
+    /// testing how clients cope with an unresponsive server: new
+    /// connections hang in the listen queue and queries on open ones don't
+    /// answer. The crate's own methods hang too until `resume`. A server
+    /// dropped while paused is resumed first.
+    pub fn pause(&self) -> Result<(), PsqlServerError> {
+        self.signal_all(Signal::Stop)?;
+        self.paused.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Lets a `pause`d server carry on with SIGCONT.
+    pub fn resume(&self) -> Result<(), PsqlServerError> {
+        self.signal_all(Signal::Continue)?;
+        self.paused.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Sends `signal` to the postmaster and its children. Each of those is
+    /// a session leader of its own, so the postmaster's process group
+    /// doesn't cover them.
+    #[cfg(unix)]
+    fn signal_all(&self, signal: Signal) -> Result<(), PsqlServerError> {
+        let signal = match signal {
+            Signal::Stop => libc::SIGSTOP,
+            Signal::Continue => libc::SIGCONT
+        };
+        let postmaster = self.postmaster_pid()?;
+        let children = child_pids(postmaster)
+            .map_err(PsqlServerError::IoError)?;
+        // the postmaster first, so it starts no new children meanwhile
+        for pid in std::iter::once(postmaster).chain(children) {
+            // a child may have exited since
+            unsafe {
+                libc::kill(pid as libc::pid_t, signal);
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn signal_all(&self, _signal: Signal) -> Result<(), PsqlServerError> {
+        Err(PsqlServerError::IoError(std::io::Error::new(
+            std::io::ErrorKind::Unsupported, "pausing the server needs unix signals")))
+    }
+
+    /// The pid of the postmaster, read from `postmaster.pid` with `pg_ctl`.
+    fn postmaster_pid(&self) -> Result<u32, PsqlServerError> {
+        if self.postgres.pg_ctl.is_none() {
+            if let Some(process) = self.child().as_ref() {
+                return Ok(process.id());
+            }
+        }
+        let pid_file = fs::read_to_string(self.data_dir.join("postmaster.pid"))
+            .map_err(PsqlServerError::IoError)?;
+        pid_file.lines().next()
+            .and_then(|line| line.trim().parse().ok())
+            .ok_or_else(|| PsqlServerError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidData, "postmaster.pid has no pid")))
+    }
+
+    /// Waits (up to `timeout`) for postgres to exit by itself, e.g. after
+    /// the code under test shut it down, and returns its exit status. The
+    /// server then counts as stopped, as after `stop`, so `restart` can
+    /// bring it back. Not available with `Lifecycle::PgCtl`, where the
+    /// crate has no handle on the postmaster.
+    pub fn wait_for_exit(&mut self, timeout: Duration) -> Result<ExitStatus, PsqlServerError> {
+        if self.postgres.pg_ctl.is_some() {
+            return Err(PsqlServerError::IoError(std::io::Error::new(
+                std::io::ErrorKind::Unsupported, "waiting for postgres to exit needs the direct lifecycle")));
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            let mut child = self.child();
+            let status = match child.as_mut() {
+                Some(process) => process.try_wait()
+                    .map_err(PsqlServerError::IoError)?,
+                None => return Err(PsqlServerError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::NotFound, "postgres was already stopped")))
+            };
+            if let Some(status) = status {
+                child.take();
+                drop(child);
+                self.log.wait_closed(Duration::from_secs(1));
+                return Ok(status);
+            }
+            drop(child);
+            if Instant::now() >= deadline {
+                return Err(PsqlServerError::TimedOut("waiting for postgres to exit".to_owned()));
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Stops the server cleanly and starts it again on the same data
+    /// directory, e.g. to check what survives a restart.
+    pub fn restart(&mut self) -> Result<(), PsqlServerError> {
+        self.stop()?;
+        let process = self.postgres.spawn(&self.log).map_err(PsqlServerError::IoError)?;
+        *self.child() = Some(process);
+        self.wait_until_ready()
+    }
+
+    fn child(&self) -> MutexGuard<'_, Option<Child>> {
+        self.process.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Whether postgres is still up, i.e. was neither stopped nor has
+    /// exited.
+    fn is_running(&self) -> bool {
+        match &self.postgres.pg_ctl {
+            Some(pg_ctl) => self.postgres.pg_ctl_running(pg_ctl),
+            None => self.child().as_mut().is_some_and(|child| matches!(child.try_wait(), Ok(None)))
+        }
+    }
+
+    /// Fails with `ServerCrashed` if postgres has exited without being
+    /// stopped.
+    fn check_running(&self) -> Result<(), PsqlServerError> {
+        let status = self.child().as_mut().and_then(|child| child.try_wait().ok().flatten());
+        match status {
+            Some(status) => {
+                self.log.wait_closed(Duration::from_secs(1));
+                Err(PsqlServerError::ServerCrashed { status, stderr_tail: self.log.tail(CRASH_LOG_LINES) })
+            },
+            None => Ok(())
+        }
+    }
+
+    /// Describes the client connections that are still open, if any, see
+    /// `PsqlServerBuilder::assert_clean_on_drop`.
+    fn open_connections_report(&self) -> Option<String> {
+        // a server that is already gone has nothing left open
+        let activity = self.activity().ok()?;
+        if activity.total_backends == 0 {
+            return None;
+        }
+        Some(format!("{} client connection(s) still open when the server was dropped \
+                      ({} active, {} idle in transaction)",
+                     activity.total_backends, activity.active, activity.idle_in_transaction))
+    }
+
+    /// The error for the fatal message the server logged, if any.
+    fn fatal_error(&self) -> Option<PsqlServerError> {
+        let fatal = self.log.fatal()?;
+        // the port was free when it was picked, but that's no guarantee
+        let port_taken = self.log.tail(CRASH_LOG_LINES).iter()
+            .any(|line| line.contains("could not bind") && line.contains("Address already in use"));
+        Some(if port_taken {
+            PsqlServerError::PortInUse(self.port)
+        } else {
+            PsqlServerError::PostgresFatal(fatal)
+        })
+    }
+
+    fn wait_until_ready(&mut self) -> Result<(), PsqlServerError> {
+        if self.postgres.pg_ctl.is_some() {
+            // `pg_ctl start -w` does the waiting
+            let process = self.child().take().unwrap();
+            let out = process.wait_with_output()
+                .map_err(PsqlServerError::IoError)?;
+            if out.status.success() {
+                return Ok(());
+            }
+            self.log.wait_closed(Duration::from_secs(1));
+            return Err(match self.fatal_error() {
+                Some(error) => error,
+                None if String::from_utf8_lossy(&out.stderr).contains("did not start in time") =>
+                    PsqlServerError::StartupTimedOut,
+                None => PsqlServerError::PostgresFailed
+            });
+        }
+        let deadline = Instant::now() + self.postgres.startup_timeout;
+        loop {
+            let exited = self.child().as_mut().unwrap().try_wait()
+                .map_err(PsqlServerError::IoError)?;
+            if let Some(_exit_code) = exited {
+                    // let the log catch up with whatever postgres said before exiting
+                    self.log.wait_closed(Duration::from_secs(1));
+                    return Err(self.fatal_error().unwrap_or(PsqlServerError::PostgresFailed));
+                }
+            match pg_isready(&self.pg_isready, self.port, &self.local_host, &self.superuser)? {
+                Readiness::Accepting => return Ok(()),
+                // once the server has logged a fatal error it isn't going to
+                // come up, even if it hasn't exited yet
+                Readiness::NoResponse => if let Some(error) = self.fatal_error() {
+                    return Err(error);
+                },
+                Readiness::Rejecting => {}
+            }
+            if Instant::now() >= deadline {
+                return Err(PsqlServerError::StartupTimedOut);
+            } else {
+                thread::sleep(Duration::from_millis(500))
+            }
+        }
+    }
+}
+
+impl fmt::Debug for PsqlServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PsqlServer {{ port: {}, location: {}", self.port, self.data_dir.display())?;
+        // only missing while the server is being dropped
+        if let Some(base_dir) = &self.base_dir {
+            write!(f, ", base_dir: {}", base_dir.path().display())?;
+        }
+        write!(f, " }}")
+    }
+}
+
+impl Drop for PsqlServer {
+    fn drop(&mut self) {
+        // a paused server would neither answer nor shut down
+        if self.paused.load(Ordering::SeqCst) {
+            let _ = self.resume();
+        }
+        // a panic while already unwinding would abort, so cleanup failures
+        // are only reported when not panicking
+        let panicking = thread::panicking();
+        // checked while the server still runs, but only reported once it
+        // is cleaned up, so a strict panic doesn't leak it
+        let unclean = if self.assert_clean && self.started { self.open_connections_report() } else { None };
+        // a server that crashed or was shut down has nothing to drop, and
+        // any other failure is only reported, as panicking here would abort
+        // a test that is already unwinding
+        if self.drop_unique_database_on_drop && self.started && self.is_running() {
+            if let Err(error) = self.drop_database(&self.database).force(true).run() {
+                eprintln!("testing-postgres: failed to drop database `{}`: {}", self.database, error);
+            }
+        }
+        let persistent = !self.data_dir.starts_with(self.base_dir.as_ref().unwrap().path());
+        if let Some(pg_ctl) = &self.postgres.pg_ctl {
+            // a `pg_ctl start` still waiting for the server
+            let process = self.child().take();
+            if let Some(mut process) = process {
+                let _ = process.kill();
+                let _ = process.wait();
+            }
+            if self.postgres.pg_ctl_running(pg_ctl) {
+                let mode = if persistent { "fast" } else { "immediate" };
+                let stopped = self.postgres.pg_ctl_stop(pg_ctl, mode);
+                if !panicking {
+                    stopped.expect("failed to stop postgres");
+                }
+            }
+        } else if let Some(mut process) = self.child().take() {
+            if let Ok(None) = process.try_wait() {
+                // a cluster that outlives the server is shut down cleanly, so
+                // the next start doesn't have to recover it
+                let killed = if persistent { shutdown(&mut process) } else { kill_postgres(&mut process) };
+                if !panicking {
+                    killed.expect("failed to kill postgres");
+                }
+            }
+            let _ = process.wait();
+        }
+        let base_dir = self.base_dir.take().unwrap();
+        registry::unregister(base_dir.path());
+        let keep = match self.cleanup {
+            CleanupPolicy::Always => false,
+            CleanupPolicy::Never => true,
+            CleanupPolicy::OnSuccess => !self.started || panicking
+        };
+        if keep {
+            keep_dir(base_dir);
+        } else {
+            let closed = base_dir.close();
+            if !panicking {
+                closed.expect("failed to delete temp dir");
+            }
+        }
+        if let Some(socket_dir) = self.socket_dir.take() {
+            let closed = socket_dir.close();
+            if !panicking {
+                closed.expect("failed to delete socket dir");
+            }
+        }
+        // removes the shared directory if this was the last server in it
+        self.parent_dir.take();
+        if let Some(message) = unclean {
+            if self.strict_clean && !panicking {
+                panic!("{}", message);
+            }
+            eprintln!("testing-postgres: {}", message);
+        }
     }
 }
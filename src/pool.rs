@@ -0,0 +1,115 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use crate::{PsqlServer, PsqlServerBuilder, PsqlServerError};
+
+/// A pool of servers started by one builder, for suites where starting a
+/// cluster per test is too slow. A server is reset with
+/// `PsqlServer::reset_cluster` (and its `test` database set up again) when
+/// its `PooledServer` is dropped, then handed to the next `acquire`.
+///
+/// Only databases are reset; roles, `ALTER SYSTEM` settings and the like
+/// carry over between users of a server.
+pub struct ServerPool {
+    shared: Arc<Shared>
+}
+
+struct Shared {
+    builder: PsqlServerBuilder,
+    max: usize,
+    state: Mutex<PoolState>,
+    returned: Condvar
+}
+
+struct PoolState {
+    idle: Vec<PsqlServer>,
+    /// Idle, handed out or being started.
+    total: usize
+}
+
+impl ServerPool {
+    /// Starts `idle` servers up front, and up to `max` in total as they are
+    /// acquired.
+    pub fn new(builder: PsqlServerBuilder, idle: usize, max: usize) -> Result<ServerPool, PsqlServerError> {
+        let idle = PsqlServer::start_many(idle.min(max), &builder)?;
+        Ok(ServerPool {
+            shared: Arc::new(Shared {
+                builder,
+                max,
+                state: Mutex::new(PoolState { total: idle.len(), idle }),
+                returned: Condvar::new()
+            })
+        })
+    }
+
+    /// Hands out an idle server, starting a new one if there is none and
+    /// the pool is below its maximum, and otherwise waiting for one to be
+    /// returned.
+    pub fn acquire(&self) -> Result<PooledServer, PsqlServerError> {
+        let mut state = self.shared.state();
+        loop {
+            if let Some(server) = state.idle.pop() {
+                return Ok(PooledServer { server: Some(server), shared: Arc::clone(&self.shared) });
+            }
+            if state.total < self.shared.max {
+                break;
+            }
+            state = self.shared.returned.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        state.total += 1;
+        drop(state);
+        match self.shared.builder.start() {
+            Ok(server) => Ok(PooledServer { server: Some(server), shared: Arc::clone(&self.shared) }),
+            Err(e) => {
+                self.shared.release_slot();
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Frees the slot of a server that won't come back.
+    fn release_slot(&self) {
+        self.state().total -= 1;
+        self.returned.notify_one();
+    }
+}
+
+/// A server borrowed from a `ServerPool`, returned to it when dropped.
+pub struct PooledServer {
+    server: Option<PsqlServer>,
+    shared: Arc<Shared>
+}
+
+impl Deref for PooledServer {
+    type Target = PsqlServer;
+
+    fn deref(&self) -> &PsqlServer {
+        self.server.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledServer {
+    fn deref_mut(&mut self) -> &mut PsqlServer {
+        self.server.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledServer {
+    fn drop(&mut self) {
+        let server = self.server.take().unwrap();
+        // a server that can't be reset (e.g. it crashed) is stopped instead
+        if self.shared.builder.recycle(&server).is_ok() {
+            self.shared.state().idle.push(server);
+            self.shared.returned.notify_one();
+        } else {
+            drop(server);
+            self.shared.release_slot();
+        }
+    }
+}
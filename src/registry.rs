@@ -0,0 +1,125 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Prefix of the base directory of each server.
+pub(crate) const BASE_DIR_PREFIX: &str = "testing-postgres.";
+
+/// Prefix of the socket directories created in /tmp when the base
+/// directory's path is too long for a socket.
+pub(crate) const SOCKET_DIR_PREFIX: &str = "testing-postgres-sock.";
+
+/// Prefix of the directories shared by the servers of `start_many`.
+pub(crate) const PARENT_DIR_PREFIX: &str = "testing-postgres-many.";
+
+/// Holds the pid of the process that created the directory. Only
+/// directories with such a marker are ever removed as orphans.
+const OWNER_FILE: &str = "testing-postgres.owner";
+
+/// Base directories of the servers running in this process.
+static LIVE_DIRS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Records this process as the owner of `dir`, which was just created.
+pub(crate) fn mark_owner(dir: &Path) -> Result<(), std::io::Error> {
+    fs::write(dir.join(OWNER_FILE), std::process::id().to_string())
+}
+
+/// Removes the owner marker of `dir`, before removing the then empty
+/// directory.
+pub(crate) fn unmark_owner(dir: &Path) {
+    let _ = fs::remove_file(dir.join(OWNER_FILE));
+}
+
+pub(crate) fn register(dir: &Path) {
+    LIVE_DIRS.lock().unwrap_or_else(|e| e.into_inner())
+        .push(dir.to_owned());
+}
+
+pub(crate) fn unregister(dir: &Path) {
+    LIVE_DIRS.lock().unwrap_or_else(|e| e.into_inner())
+        .retain(|live| live != dir);
+}
+
+fn live_dirs() -> Vec<PathBuf> {
+    LIVE_DIRS.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Removes abandoned server directories in `parent`, returning the removed
+/// paths.
+pub(crate) fn cleanup_orphans(parent: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    let live = live_dirs();
+    let mut removed = Vec::new();
+    for entry in fs::read_dir(parent)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with(PARENT_DIR_PREFIX) && entry.file_type()?.is_dir() {
+            let path = entry.path();
+            removed.extend(cleanup_orphans(&path)?);
+            // still in use while any server in it is, or one kept its directory
+            let empty = fs::read_dir(&path)?.filter_map(Result::ok)
+                .all(|entry| entry.file_name() == OWNER_FILE);
+            if empty && is_abandoned(&path) {
+                unmark_owner(&path);
+                if fs::remove_dir(&path).is_ok() {
+                    removed.push(path);
+                }
+            }
+            continue;
+        }
+        if !(name.starts_with(BASE_DIR_PREFIX) || name.starts_with(SOCKET_DIR_PREFIX))
+            || !entry.file_type()?.is_dir() {
+                continue;
+            }
+        let path = entry.path();
+        if live.contains(&path) || !is_abandoned(&path) {
+            continue;
+        }
+        // another process may be cleaning up the same directory
+        if fs::remove_dir_all(&path).is_ok() {
+            removed.push(path);
+        }
+    }
+    Ok(removed)
+}
+
+/// Whether the process named by the owner marker of `dir` is gone, and no
+/// server still runs in it, judged by `postmaster.pid` in the data
+/// directory or the socket lock files. A directory without a marker was not
+/// created by this crate, or is still being set up, and is never abandoned.
+fn is_abandoned(dir: &Path) -> bool {
+    let owner = fs::read_to_string(dir.join(OWNER_FILE)).ok()
+        .and_then(|contents| contents.trim().parse().ok());
+    match owner {
+        Some(pid) if !process_alive(pid) => {}
+        _ => return false
+    }
+
+    let mut pid_files = vec![dir.join("data").join("postmaster.pid")];
+    for socket_dir in &[dir.to_owned(), dir.join("tmp")] {
+        if let Ok(entries) = fs::read_dir(socket_dir) {
+            pid_files.extend(entries.filter_map(Result::ok)
+                             .map(|entry| entry.path())
+                             .filter(|path| path.to_string_lossy().ends_with(".lock")));
+        }
+    }
+
+    // a server may outlive the process that started it
+    !pid_files.iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .filter_map(|contents| contents.lines().next()?.trim().parse().ok())
+        .any(process_alive)
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    // signal 0 only checks whether the process exists
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    // without a way to check, never treat a directory as abandoned
+    true
+}
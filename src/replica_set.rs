@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+use crate::{PsqlServer, PsqlServerBuilder, PsqlServerError};
+
+/// A primary with hot standbys streaming from it, for testing read/write
+/// splitting.
+pub struct ReplicaSet {
+    // declared first so the standbys are stopped before their primary
+    standbys: Vec<PsqlServer>,
+    primary: PsqlServer
+}
+
+impl ReplicaSet {
+    /// Starts a primary and `standbys` standbys of it, all configured by
+    /// `builder`.
+    pub fn start(builder: &PsqlServerBuilder, standbys: usize) -> Result<ReplicaSet, PsqlServerError> {
+        let primary = builder.start()?;
+        let standbys = (0..standbys)
+            .map(|_| builder.start_standby(&primary))
+            .collect::<Result<_, _>>()?;
+        Ok(ReplicaSet { standbys, primary })
+    }
+
+    pub fn primary(&self) -> &PsqlServer {
+        &self.primary
+    }
+
+    pub fn standbys(&self) -> &[PsqlServer] {
+        &self.standbys
+    }
+
+    /// The primary's `connection_string`, for writes.
+    pub fn primary_url(&self) -> String {
+        self.primary.connection_string()
+    }
+
+    /// The first standby's `read_only_connection_string`, for reads, or
+    /// `None` if the set has no standbys.
+    pub fn replica_url(&self) -> Option<String> {
+        self.standbys.first().map(PsqlServer::read_only_connection_string)
+    }
+
+    /// Waits until every standby has replayed everything written to the
+    /// primary so far.
+    pub fn wait_for_replay(&self, timeout: Duration) -> Result<(), PsqlServerError> {
+        let lsn = self.primary.current_wal_lsn()?;
+        for standby in &self.standbys {
+            standby.wait_for_replay_lsn(&lsn, timeout)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+#[cfg(feature = "regex")]
+use std::time::Instant;
+
+/// How many of the most recent stderr lines are kept.
+const MAX_LINES: usize = 1000;
+
+/// Messages the server logs for connections it rejects while starting or
+/// stopping, which aren't fatal to the server itself.
+const CLIENT_FATALS: &[&str] = &[
+    "the database system is starting up",
+    "the database system is shutting down",
+    "the database system is not yet accepting connections"
+];
+
+/// How many lines a `log_stream` may fall behind before it skips ahead.
+#[cfg(feature = "tokio")]
+const STREAM_CAPACITY: usize = 1024;
+
+/// The stderr output of the postgres process, read on a background thread
+/// so postgres never blocks on a full pipe.
+pub(crate) struct ServerLog {
+    state: Mutex<LogState>,
+    changed: Condvar,
+    /// Where every line is also appended, see `PsqlServerBuilder::log_file`.
+    file: Option<Mutex<File>>,
+    #[cfg(feature = "tokio")]
+    stream: tokio::sync::broadcast::Sender<String>
+}
+
+impl Default for ServerLog {
+    fn default() -> ServerLog {
+        ServerLog {
+            state: Mutex::default(),
+            changed: Condvar::new(),
+            file: None,
+            #[cfg(feature = "tokio")]
+            stream: tokio::sync::broadcast::channel(STREAM_CAPACITY).0
+        }
+    }
+}
+
+#[derive(Default)]
+struct LogState {
+    lines: VecDeque<String>,
+    /// How many lines were ever logged, including those dropped from `lines`.
+    total: usize,
+    fatal: Option<String>,
+    closed: bool
+}
+
+impl ServerLog {
+    /// A log that also appends every line to the file at `path`.
+    pub(crate) fn with_file(path: &Path) -> Result<ServerLog, std::io::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(ServerLog { file: Some(Mutex::new(file)), ..ServerLog::default() })
+    }
+
+    /// Starts reading `pipe`, the stderr of a newly spawned postgres.
+    pub(crate) fn capture<R: Read + Send + 'static>(self: &Arc<Self>, pipe: R) {
+        {
+            let mut state = self.state();
+            state.fatal = None;
+            state.closed = false;
+        }
+        let log = Arc::clone(self);
+        thread::spawn(move || {
+            for line in BufReader::new(pipe).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break
+                };
+                if let Some(file) = &log.file {
+                    let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+                    // a full disk shouldn't stop the server's output being read
+                    let _ = writeln!(file, "{}", line);
+                }
+                let mut state = log.state();
+                if state.fatal.is_none() && is_fatal(&line) {
+                    state.fatal = Some(line.clone());
+                }
+                if state.lines.len() == MAX_LINES {
+                    state.lines.pop_front();
+                }
+                #[cfg(feature = "tokio")]
+                {
+                    // fails only when nobody is subscribed
+                    let _ = log.stream.send(line.clone());
+                }
+                state.lines.push_back(line);
+                state.total += 1;
+                log.changed.notify_all();
+            }
+            log.state().closed = true;
+            log.changed.notify_all();
+        });
+    }
+
+    /// The first FATAL or PANIC message the server logged since it was
+    /// last spawned.
+    pub(crate) fn fatal(&self) -> Option<String> {
+        self.state().fatal.clone()
+    }
+
+    /// Up to `max` of the retained lines, oldest first.
+    pub(crate) fn lines(&self, max: usize) -> Vec<String> {
+        self.state().lines.iter().take(max).cloned().collect()
+    }
+
+    /// Up to `max` of the most recent lines, oldest first.
+    pub(crate) fn tail(&self, max: usize) -> Vec<String> {
+        let state = self.state();
+        state.lines.iter().skip(state.lines.len().saturating_sub(max)).cloned().collect()
+    }
+
+    /// Waits (up to `timeout`) for a line that `matches`, checking the
+    /// retained lines first.
+    #[cfg(feature = "regex")]
+    pub(crate) fn wait_for_line<F: Fn(&str) -> bool>(&self, matches: F, timeout: Duration) -> Option<String> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state();
+        let mut checked = 0;
+        loop {
+            let first = state.total - state.lines.len();
+            let found = state.lines.iter()
+                .skip(checked.max(first) - first)
+                .find(|line| matches(line));
+            if let Some(line) = found {
+                return Some(line.clone());
+            }
+            checked = state.total;
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            state = self.changed.wait_timeout(state, deadline - now)
+                .unwrap_or_else(|e| e.into_inner()).0;
+        }
+    }
+
+    /// Waits (up to `timeout`) for postgres to close stderr, so everything
+    /// it logged before exiting has been read.
+    pub(crate) fn wait_closed(&self, timeout: Duration) {
+        let state = self.state();
+        let _ = self.changed.wait_timeout_while(state, timeout, |state| !state.closed);
+    }
+
+    /// A receiver for every line logged from now on.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn subscribe(&self) -> tokio::sync::broadcast::Receiver<String> {
+        self.stream.subscribe()
+    }
+
+    fn state(&self) -> MutexGuard<'_, LogState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn is_fatal(line: &str) -> bool {
+    (line.contains("FATAL:") || line.contains("PANIC:"))
+        && !CLIENT_FATALS.iter().any(|message| line.contains(message))
+}
@@ -0,0 +1,136 @@
+use std::io::{BufRead, BufReader, PipeReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::thread;
+
+use crate::{keep_dir, CleanupPolicy, DataStore, PsqlServerError};
+
+/// What postgres prints when it is ready for the next command.
+const PROMPT: &[u8] = b"backend> ";
+
+/// A cluster run with `postgres --single`, the standalone backend used for
+/// offline maintenance, see `PsqlServerBuilder::start_single_user`. There is
+/// no network listener; commands go to the backend's stdin and its output
+/// (stderr included) is read from its stdout.
+pub struct SingleUserSession {
+    process: Child,
+    stdin: Option<ChildStdin>,
+    output: BufReader<PipeReader>,
+    base_dir: Option<Box<dyn DataStore>>,
+    cleanup: CleanupPolicy,
+    /// Set once the first prompt was seen, for `CleanupPolicy::OnSuccess`.
+    started: bool
+}
+
+impl SingleUserSession {
+    /// Spawns `postgres` with `args` and waits for its first prompt.
+    pub(crate) fn spawn(program: &str, args: Vec<String>, base_dir: Box<dyn DataStore>,
+                        cleanup: CleanupPolicy) -> Result<SingleUserSession, PsqlServerError> {
+        let (reader, writer) = std::io::pipe()
+            .map_err(PsqlServerError::IoError)?;
+        // one pipe for both, so errors show up in order with the results
+        let stderr = writer.try_clone()
+            .map_err(PsqlServerError::IoError)?;
+        let mut process = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(writer)
+            .stderr(stderr)
+            .spawn()
+            .map_err(PsqlServerError::IoError)?;
+        let stdin = process.stdin.take();
+        let mut session = SingleUserSession {
+            process,
+            stdin,
+            output: BufReader::new(reader),
+            base_dir: Some(base_dir),
+            cleanup,
+            started: false
+        };
+        // the banner
+        session.recv()?;
+        session.started = true;
+        Ok(session)
+    }
+
+    /// Sends one SQL command. A trailing `;` is added if missing.
+    pub fn send(&mut self, sql: &str) -> Result<(), PsqlServerError> {
+        let stdin = self.stdin.as_mut()
+            .ok_or_else(|| PsqlServerError::PsqlFailed("session is closed".to_owned()))?;
+        let sql = sql.trim_end();
+        let terminator = if sql.ends_with(';') { "" } else { ";" };
+        // with `-j`, a command ends at a semicolon followed by an empty line
+        write!(stdin, "{}{}\n\n", sql, terminator)
+            .and_then(|_| stdin.flush())
+            .map_err(PsqlServerError::IoError)
+    }
+
+    /// Reads what the backend printed up to its next prompt: the result
+    /// rows in postgres' debug format, or any error messages. Fails with
+    /// `PostgresFatal` (carrying the FATAL message, if there is one) if the
+    /// backend exits instead.
+    pub fn recv(&mut self) -> Result<String, PsqlServerError> {
+        let mut output = Vec::new();
+        loop {
+            let available = self.output.fill_buf()
+                .map_err(PsqlServerError::IoError)?;
+            if available.is_empty() {
+                let output = String::from_utf8_lossy(&output);
+                let fatal = output.lines()
+                    .find(|line| line.contains("FATAL:") || line.contains("PANIC:"))
+                    .or_else(|| output.lines().rev().find(|line| !line.trim().is_empty()));
+                return Err(match fatal {
+                    Some(fatal) => PsqlServerError::PostgresFatal(fatal.trim().to_owned()),
+                    None => PsqlServerError::PostgresFailed
+                });
+            }
+            // the prompt has no newline after it, so read byte by byte
+            output.push(available[0]);
+            self.output.consume(1);
+            if output.ends_with(PROMPT) {
+                output.truncate(output.len() - PROMPT.len());
+                return Ok(String::from_utf8_lossy(&output).trim().to_owned());
+            }
+        }
+    }
+
+    /// Closes stdin, which makes the backend shut down (with a checkpoint),
+    /// and waits for it to exit.
+    pub fn close(mut self) -> Result<(), PsqlServerError> {
+        self.shut_down()
+    }
+
+    fn shut_down(&mut self) -> Result<(), PsqlServerError> {
+        if self.stdin.take().is_none() {
+            return Ok(());
+        }
+        let status = self.process.wait()
+            .map_err(PsqlServerError::IoError)?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(PsqlServerError::PostgresFailed)
+        }
+    }
+}
+
+impl Drop for SingleUserSession {
+    fn drop(&mut self) {
+        let panicking = thread::panicking();
+        // a backend that failed has already said why, through `recv`
+        let _ = self.shut_down();
+        let base_dir = self.base_dir.take().unwrap();
+        let keep = match self.cleanup {
+            CleanupPolicy::Always => false,
+            CleanupPolicy::Never => true,
+            CleanupPolicy::OnSuccess => !self.started || panicking
+        };
+        if keep {
+            keep_dir(base_dir);
+        } else {
+            let closed = base_dir.close();
+            if !panicking {
+                closed.expect("failed to delete temp dir");
+            }
+        }
+    }
+}
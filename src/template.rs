@@ -0,0 +1,78 @@
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::{copy_dir, PsqlServer, PsqlServerBuilder, PsqlServerError};
+
+/// Serialises builds within the process; the lock file does the same
+/// across processes, where `flock` is available.
+static BUILDING: Mutex<()> = Mutex::new(());
+
+/// A cluster built once and then copied for every server started from it
+/// with `PsqlServerBuilder::template_data_dir`, e.g. with migrations already
+/// applied.
+#[derive(Debug, Clone)]
+pub struct Template {
+    path: PathBuf
+}
+
+impl Template {
+    /// The template named `key` in the system's temporary directory,
+    /// building it first if it doesn't exist yet: a server is started with
+    /// `builder`, `build` runs against it (e.g. applying migrations to the
+    /// `test` database) and the stopped cluster becomes the template.
+    ///
+    /// Callers asking for the same key at the same time, from other threads
+    /// or (on unix) other processes, wait for the first build instead of
+    /// starting their own. A template survives the test run, so `key`
+    /// should change whenever what `build` does changes, e.g. by including
+    /// a hash of the migrations. A failed build leaves nothing behind.
+    pub fn get_or_build<F>(builder: &PsqlServerBuilder, key: &str, build: F) -> Result<Template, PsqlServerError>
+    where F: FnOnce(&PsqlServer) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let name = format!("testing-postgres-template-{}", key);
+        let dir = std::env::temp_dir();
+        let path = dir.join(&name);
+        let _building = BUILDING.lock().unwrap_or_else(|e| e.into_inner());
+        let _lock = lock_file(&dir.join(format!("{}.lock", name)))?;
+        if !path.exists() {
+            build_into(builder, &path, &dir.join(format!("{}.partial", name)), build)?;
+        }
+        Ok(Template { path })
+    }
+
+    /// The template's cluster, for `PsqlServerBuilder::template_data_dir`.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Builds the template in `partial`, then moves it to `path`, so a crash
+/// midway can't leave a template that looks complete.
+fn build_into<F>(builder: &PsqlServerBuilder, path: &Path, partial: &Path, build: F) -> Result<(), PsqlServerError>
+where F: FnOnce(&PsqlServer) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut server = builder.start()?;
+    build(&server).map_err(PsqlServerError::MigrationFailed)?;
+    server.stop()?;
+    if partial.exists() {
+        fs::remove_dir_all(partial)
+            .map_err(PsqlServerError::IoError)?;
+    }
+    copy_dir(server.location(), partial)
+        .and_then(|_| fs::rename(partial, path))
+        .map_err(PsqlServerError::IoError)
+}
+
+/// Opens (creating it if needed) and exclusively locks `path`, until the
+/// returned file is closed.
+fn lock_file(path: &Path) -> Result<File, PsqlServerError> {
+    let file = File::create(path)
+        .map_err(PsqlServerError::IoError)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::AsRawFd;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(PsqlServerError::IoError(std::io::Error::last_os_error()));
+        }
+    }
+    Ok(file)
+}
@@ -0,0 +1,335 @@
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+use testing_postgres::{AuthMethod, BackslashQuote, IsolationLevel, PsqlServer};
+
+/// Postgres refuses to run as root, so tests that start a server are
+/// skipped there.
+fn skip() -> bool {
+    #[cfg(unix)]
+    {
+        if unsafe { libc::geteuid() } == 0 {
+            eprintln!("skipping: postgres can't be started as root");
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(unix)]
+#[test]
+fn drop_kills_busy_backends() {
+    if skip() {
+        return;
+    }
+    let server = PsqlServer::start().unwrap();
+    let mut connection = server.connect("test").unwrap();
+    let pid = connection.query("SELECT pg_backend_pid()").unwrap()[0][0].clone();
+    // a backend spinning like this never checks whether the postmaster died
+    let query = thread::spawn(move || connection.execute("DO $$ BEGIN LOOP END LOOP; END $$"));
+    let mut monitor = server.connect("postgres").unwrap();
+    let active = format!("SELECT count(*) FROM pg_stat_activity WHERE pid = {} AND state = 'active'", pid);
+    while monitor.query(&active).unwrap()[0][0] == "0" {
+        thread::sleep(Duration::from_millis(10));
+    }
+    drop(monitor);
+    drop(server);
+    // an exited backend may linger as a zombie where nothing reaps orphans
+    let out = Command::new("ps").args(["-o", "stat=", "-p", &pid]).output().unwrap();
+    let state = String::from_utf8_lossy(&out.stdout);
+    assert!(state.trim().is_empty() || state.starts_with('Z'), "backend {} is still running", pid);
+    assert!(query.join().unwrap().is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn drop_after_shutdown_with_unique_database() {
+    if skip() {
+        return;
+    }
+    let dir = tempdir::TempDir::new("testing-postgres-data").unwrap();
+    let mut server = PsqlServer::builder()
+        .data_dir(dir.path().join("data"))
+        .unique_database_name(true)
+        .start()
+        .unwrap();
+    let pid_file = std::fs::read_to_string(server.location().join("postmaster.pid")).unwrap();
+    let pid = pid_file.lines().next().unwrap();
+    assert!(Command::new("kill").args(["-TERM", pid]).status().unwrap().success());
+    server.wait_for_exit(Duration::from_secs(30)).unwrap();
+    // used to panic, failing to drop the database
+    drop(server);
+}
+
+/// The rows in table `t` of the `test` database.
+fn count(server: &PsqlServer) -> String {
+    server.connect("test").unwrap().query("SELECT count(*) FROM t").unwrap()[0][0].clone()
+}
+
+#[test]
+fn snapshots_of_a_persistent_data_dir() {
+    if skip() {
+        return;
+    }
+    let dir = tempdir::TempDir::new("testing-postgres-data").unwrap();
+    let mut server = PsqlServer::builder()
+        .data_dir(dir.path().join("data"))
+        .start()
+        .unwrap();
+    server.connect("test").unwrap().execute("CREATE TABLE t (i int); INSERT INTO t VALUES (1)").unwrap();
+    let snapshot = server.snapshot().unwrap();
+    assert!(!snapshot.path().starts_with(dir.path()));
+    server.connect("test").unwrap().execute("INSERT INTO t VALUES (2)").unwrap();
+    server.restore_snapshot(&snapshot).unwrap();
+    assert_eq!(count(&server), "1");
+
+    // a failed restore leaves the server running on its data
+    server.connect("test").unwrap().execute("INSERT INTO t VALUES (2)").unwrap();
+    std::fs::remove_dir_all(snapshot.path()).unwrap();
+    assert!(server.restore_snapshot(&snapshot).is_err());
+    assert_eq!(count(&server), "2");
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn retries_without_fallible_params() {
+    if skip() {
+        return;
+    }
+    // more shared memory than any test host has
+    let server = PsqlServer::builder()
+        .fallible_config_param("shared_buffers", "100TB")
+        .start()
+        .unwrap();
+    assert_ne!(server.show("shared_buffers").unwrap(), "100TB");
+}
+
+#[test]
+fn custom_superuser_is_used_throughout() {
+    if skip() {
+        return;
+    }
+    let server = PsqlServer::builder().superuser("alice").start().unwrap();
+    let mut connection = server.connect("test").unwrap();
+    // initdb created no `postgres` role that a tool could fall back to
+    let rows = connection.query(
+        "SELECT current_user, pg_get_userbyid(datdba), (SELECT count(*) FROM pg_roles WHERE rolname = 'postgres')
+         FROM pg_database WHERE datname = current_database()").unwrap();
+    assert_eq!(rows, vec![vec!["alice".to_owned(), "alice".to_owned(), "0".to_owned()]]);
+    connection.execute("CREATE TABLE t (i int)").unwrap();
+    assert!(server.dump_schema("test").unwrap().contains("OWNER TO alice"));
+    assert!(server.connection_string().contains("alice"));
+    assert_eq!(server.superuser(), "alice");
+}
+
+#[test]
+fn memory_settings() {
+    if skip() {
+        return;
+    }
+    let server = PsqlServer::builder()
+        .work_mem("64MB")
+        .maintenance_work_mem("256MB")
+        .effective_cache_size("4GB")
+        .start()
+        .unwrap();
+    assert_eq!(server.show("work_mem").unwrap(), "64MB");
+    assert_eq!(server.show("maintenance_work_mem").unwrap(), "256MB");
+    assert_eq!(server.show("effective_cache_size").unwrap(), "4GB");
+}
+
+#[test]
+fn default_transaction_isolation() {
+    if skip() {
+        return;
+    }
+    let levels = [IsolationLevel::ReadUncommitted, IsolationLevel::ReadCommitted,
+                  IsolationLevel::RepeatableRead, IsolationLevel::Serializable];
+    for level in levels {
+        let server = PsqlServer::builder().default_transaction_isolation(level).start().unwrap();
+        assert_eq!(server.show("default_transaction_isolation").unwrap(), level.name());
+        let rows = server.connect("test").unwrap().query("SHOW transaction_isolation").unwrap();
+        assert_eq!(rows[0][0], level.name());
+    }
+}
+
+#[test]
+fn prepared_transactions() {
+    if skip() {
+        return;
+    }
+    let prepare = "BEGIN; CREATE TABLE t (i int); PREPARE TRANSACTION 'tx'";
+    let server = PsqlServer::start().unwrap();
+    let error = server.connect("test").unwrap().execute(prepare).unwrap_err();
+    assert!(error.to_string().contains("prepared transactions are disabled"), "{}", error);
+
+    let server = PsqlServer::builder().max_prepared_transactions(2).start().unwrap();
+    let mut connection = server.connect("test").unwrap();
+    connection.execute(prepare).unwrap();
+    connection.execute("COMMIT PREPARED 'tx'").unwrap();
+    assert_eq!(count(&server), "0");
+}
+
+/// Whether the plan of a count over a small table uses parallel workers,
+/// with parallelism made as cheap as it gets.
+fn plans_parallel(server: &PsqlServer) -> bool {
+    let mut connection = server.connect("test").unwrap();
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS t AS SELECT i FROM generate_series(1, 10000) i;
+         ANALYZE t;
+         SET parallel_setup_cost = 0;
+         SET parallel_tuple_cost = 0;
+         SET min_parallel_table_scan_size = 0").unwrap();
+    let plan = connection.query("EXPLAIN SELECT count(*) FROM t").unwrap();
+    plan.iter().any(|row| row[0].contains("Gather"))
+}
+
+#[test]
+fn parallel_workers() {
+    if skip() {
+        return;
+    }
+    let server = PsqlServer::builder()
+        .max_worker_processes(8)
+        .max_parallel_workers(4)
+        .max_parallel_workers_per_gather(2)
+        .start()
+        .unwrap();
+    assert_eq!(server.show("max_worker_processes").unwrap(), "8");
+    assert_eq!(server.show("max_parallel_workers").unwrap(), "4");
+    assert!(plans_parallel(&server));
+
+    let server = PsqlServer::builder().max_parallel_workers_per_gather(0).start().unwrap();
+    assert!(!plans_parallel(&server));
+}
+
+/// Creates `count` tables in a single transaction.
+fn create_tables(server: &PsqlServer, count: u32) -> Result<(), testing_postgres::PsqlServerError> {
+    server.connect("test").unwrap().execute(&format!(
+        "DO $$ BEGIN FOR i IN 1..{} LOOP EXECUTE format('CREATE TABLE t%s (i int)', i); END LOOP; END $$", count))
+}
+
+#[test]
+fn max_locks_per_transaction() {
+    if skip() {
+        return;
+    }
+    // the shared lock table has room for max_locks_per_transaction locks
+    // per connection slot
+    let server = PsqlServer::builder()
+        .config_param("max_connections", "10")
+        .max_locks_per_transaction(10)
+        .start()
+        .unwrap();
+    let error = create_tables(&server, 3000).unwrap_err();
+    assert!(error.to_string().contains("max_locks_per_transaction"), "{}", error);
+
+    let server = PsqlServer::builder()
+        .config_param("max_connections", "10")
+        .max_locks_per_transaction(1024)
+        .start()
+        .unwrap();
+    assert_eq!(server.show("max_locks_per_transaction").unwrap(), "1024");
+    create_tables(&server, 3000).unwrap();
+}
+
+#[test]
+fn string_literal_settings() {
+    if skip() {
+        return;
+    }
+    let strict = PsqlServer::builder()
+        .standard_conforming_strings(true)
+        .backslash_quote(BackslashQuote::Off)
+        .start()
+        .unwrap();
+    assert_eq!(strict.show("backslash_quote").unwrap(), "off");
+    let error = strict.connect("test").unwrap().query(r"SELECT E'it\'s'").unwrap_err();
+    assert!(error.to_string().contains(r"unsafe use of \'"), "{}", error);
+
+    let lenient = PsqlServer::builder()
+        .standard_conforming_strings(false)
+        .escape_string_warning(true)
+        .backslash_quote(BackslashQuote::On)
+        .start()
+        .unwrap();
+    let mut connection = lenient.connect("test").unwrap();
+    assert_eq!(connection.query(r"SELECT 'it\'s'").unwrap(), vec![vec!["it's".to_owned()]]);
+    let notices = connection.take_notices();
+    assert!(notices.iter().any(|notice| notice.contains("nonstandard use of")), "{:?}", notices);
+
+    let quiet = PsqlServer::builder()
+        .standard_conforming_strings(false)
+        .escape_string_warning(false)
+        .start()
+        .unwrap();
+    let mut connection = quiet.connect("test").unwrap();
+    connection.query(r"SELECT 'a\\b'").unwrap();
+    assert!(connection.take_notices().is_empty());
+}
+
+#[test]
+fn jit() {
+    if skip() {
+        return;
+    }
+    assert_eq!(PsqlServer::builder().jit(false).start().unwrap().show("jit").unwrap(), "off");
+    assert_eq!(PsqlServer::builder().jit(true).start().unwrap().show("jit").unwrap(), "on");
+}
+
+#[test]
+fn idle_session_timeout() {
+    if skip() {
+        return;
+    }
+    let server = PsqlServer::builder().idle_session_timeout(Duration::from_millis(200)).start().unwrap();
+    assert_eq!(server.show("idle_session_timeout").unwrap(), "200ms");
+    let mut connection = server.connect("test").unwrap();
+    connection.execute("SELECT 1").unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let error = connection.execute("SELECT 1").unwrap_err();
+    assert!(error.to_string().contains("idle-session timeout"), "{}", error);
+}
+
+#[test]
+fn external_server_from_parts() {
+    if skip() {
+        return;
+    }
+    let server = PsqlServer::builder()
+        .auth_method(AuthMethod::ScramSha256)
+        .superuser_password(Some("secret"))
+        .start()
+        .unwrap();
+    let external = PsqlServer::connect_external_parts(
+        "127.0.0.1", server.port(), server.superuser(), server.password(), "test").unwrap();
+    assert_eq!(external.connection_string(), server.connection_string());
+    external.connect("test").unwrap().execute("CREATE TABLE t (i int)").unwrap();
+    // the server is left alone
+    drop(external);
+    assert_eq!(count(&server), "0");
+
+    assert!(PsqlServer::connect_external_parts(
+        "127.0.0.1", server.port(), server.superuser(), Some("wrong"), "test").is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn cleanup_orphans_only_removes_marked_dirs() {
+    let mut child = Command::new("true").spawn().unwrap();
+    let dead_pid = child.id();
+    child.wait().unwrap();
+    let temp = std::env::temp_dir();
+    let unmarked = temp.join(format!("testing-postgres.unmarked-{}", std::process::id()));
+    let orphan = temp.join(format!("testing-postgres.orphan-{}", std::process::id()));
+    std::fs::create_dir_all(unmarked.join("data")).unwrap();
+    std::fs::create_dir_all(orphan.join("data")).unwrap();
+    std::fs::write(orphan.join("testing-postgres.owner"), dead_pid.to_string()).unwrap();
+
+    let removed = PsqlServer::cleanup_orphans().unwrap();
+    assert!(removed.contains(&orphan), "{:?}", removed);
+    assert!(!orphan.exists());
+    assert!(unmarked.exists());
+    std::fs::remove_dir_all(&unmarked).unwrap();
+}